@@ -0,0 +1,178 @@
+use specs::prelude::*;
+use rltk::{Point, BaseMap};
+use super::{Map, TileType, Monster, Item, Name, Position, CombatStats, Command};
+use super::dijkstra_system::{build_dijkstra, downhill_step};
+
+/// 自動で続ける移動の種類
+#[derive(PartialEq, Copy, Clone)]
+pub enum AutoMoveKind {
+  /// まだ見ていない場所へ歩き続ける
+  Explore,
+  /// 決めたタイル (マップのインデックス) まで歩く
+  Travel{ destination : usize }
+}
+
+/// 何ターンも続けて自動で歩く行動。1ターンに1歩ずつ、ふつうのMoveコマンドとして実行するので、
+/// その間もモンスターは動くし、リプレイにも1歩ずつ残る
+pub struct AutoMove {
+  pub kind : AutoMoveKind,
+  /// 前の1歩のときのHP。変わったら止まる
+  last_hp : i32,
+  /// もう見たアイテム。新しく見えたら止まる
+  seen_items : Vec<Entity>,
+  /// Travelで、これから通るタイル。毎歩探し直すと道が揺れて行ったり来たりするので、ふさがれるまでは同じ道を使う
+  path : Vec<usize>
+}
+
+/// プレイヤーから見えているモンスター
+fn visible_monsters(ecs : &World) -> Vec<Entity> {
+  let map = ecs.fetch::<Map>();
+  let entities = ecs.entities();
+  let monsters = ecs.read_storage::<Monster>();
+  let positions = ecs.read_storage::<Position>();
+
+  (&entities, &monsters, &positions).join()
+    .filter(|(_, _, pos)| map.visible_tiles[map.xy_idx(pos.x, pos.y)])
+    .map(|(entity, _, _)| entity)
+    .collect()
+}
+
+/// プレイヤーから見えている、床に落ちているアイテム
+fn visible_items(ecs : &World) -> Vec<Entity> {
+  let map = ecs.fetch::<Map>();
+  let entities = ecs.entities();
+  let items = ecs.read_storage::<Item>();
+  let positions = ecs.read_storage::<Position>();
+
+  (&entities, &items, &positions).join()
+    .filter(|(_, _, pos)| map.visible_tiles[map.xy_idx(pos.x, pos.y)])
+    .map(|(entity, _, _)| entity)
+    .collect()
+}
+
+fn player_hp(ecs : &World) -> i32 {
+  let player_entity = ecs.fetch::<Entity>();
+  ecs.read_storage::<CombatStats>().get(*player_entity).map_or(0, |stats| stats.hp)
+}
+
+fn name_of(ecs : &World, entity : Entity) -> String {
+  ecs.read_storage::<Name>().get(entity).map_or("something".to_string(), |name| name.name.clone())
+}
+
+/// 今いるところから隣のタイルnextへのMoveコマンド
+fn move_to(map : &Map, player_pos : Point, next : usize) -> Command {
+  Command::Move{
+    delta_x: next as i32 % map.width - player_pos.x,
+    delta_y: next as i32 / map.width - player_pos.y
+  }
+}
+
+/// まだ明らかになっていないタイルへ向かう1歩。行けるところがもうなければNone
+fn explore_step(ecs : &World) -> Option<Command> {
+  let map = ecs.fetch::<Map>();
+  let player_pos = ecs.fetch::<Point>();
+
+  // 明らかになっていないタイルをすべて目的地にする
+  let goals : Vec<(usize, f32)> = map.revealed_tiles.iter().enumerate()
+    .filter(|(_, revealed)| !**revealed)
+    .map(|(idx, _)| (idx, 0.0))
+    .collect();
+  let values = build_dijkstra(&map, &goals);
+
+  let player_idx = map.xy_idx(player_pos.x, player_pos.y);
+  let next = downhill_step(&map, &values, player_idx)?;
+  Some(move_to(&map, *player_pos, next))
+}
+
+/// 見つけている下り階段のタイル
+pub fn known_stairs(ecs : &World) -> Option<usize> {
+  let map = ecs.fetch::<Map>();
+  map.tiles.iter().enumerate()
+    .position(|(idx, tile)| *tile == TileType::DownStairs && map.revealed_tiles[idx])
+}
+
+impl AutoMove {
+  /// 自動移動を始める。モンスターが見えているときは危ないので始めない。
+  /// 1歩目から進めないとき (もう着いている、道がない) もErrを返す
+  pub fn start(ecs : &World, kind : AutoMoveKind) -> Result<AutoMove, String> {
+    if let Some(monster) = visible_monsters(ecs).first() {
+      return Err(format!("Not with {} in view.", name_of(ecs, *monster)));
+    }
+    let mut auto_move = AutoMove{ kind, last_hp: player_hp(ecs), seen_items: visible_items(ecs), path: Vec::new() };
+    auto_move.step(ecs)?;
+    Ok(auto_move)
+  }
+
+  /// 種類ごとの次の1歩
+  fn step(&mut self, ecs : &World) -> Result<Command, String> {
+    match self.kind {
+      AutoMoveKind::Explore => explore_step(ecs).ok_or_else(|| "Nothing left to explore.".to_string()),
+      AutoMoveKind::Travel{ destination } => self.travel_step(ecs, destination)
+    }
+  }
+
+  /// destinationへ向かう1歩。道はA*で探し、モンスターにふさがれたら探し直す
+  fn travel_step(&mut self, ecs : &World, destination : usize) -> Result<Command, String> {
+    let map = ecs.fetch::<Map>();
+    let player_pos = ecs.fetch::<Point>();
+
+    let player_idx = map.xy_idx(player_pos.x, player_pos.y);
+    if player_idx == destination {
+      return Err("You arrive.".to_string());
+    }
+    // 誰かが立っているタイルには入れない (入ろうとすると攻撃になる)
+    if map.blocked[destination] {
+      return Err("Something is in the way.".to_string());
+    }
+
+    // もう着いたタイルは道から外す
+    if let Some(walked) = self.path.iter().position(|idx| *idx == player_idx) {
+      self.path.drain(..= walked);
+    }
+    let exits = map.get_available_exits(player_idx);
+    let open = matches!(self.path.first(), Some(next) if exits.iter().any(|(exit, _)| exit == next));
+    if !open {
+      let path = rltk::a_star_search(player_idx as i32, destination as i32, &*map);
+      // steps[0]は今いるところ
+      if !path.success || path.steps.len() < 2 {
+        return Err("You can't find a way there.".to_string());
+      }
+      self.path = path.steps[1 ..].to_vec();
+    }
+    Ok(move_to(&map, *player_pos, self.path[0]))
+  }
+
+  /// 止まるべき出来事があればその説明
+  fn interruption(&mut self, ecs : &World) -> Option<String> {
+    if let Some(monster) = visible_monsters(ecs).first() {
+      return Some(format!("{} comes into view.", name_of(ecs, *monster)));
+    }
+
+    let hp = player_hp(ecs);
+    if hp != self.last_hp {
+      self.last_hp = hp;
+      return Some("Your health changed.".to_string());
+    }
+
+    // 行き先を決めて歩いているときは、アイテムくらいでは止まらない
+    if self.kind != AutoMoveKind::Explore {
+      return None;
+    }
+    let new_item = visible_items(ecs).into_iter().find(|item| !self.seen_items.contains(item));
+    if let Some(item) = new_item {
+      self.seen_items.push(item);
+      return Some(format!("You see {}.", name_of(ecs, item)));
+    }
+
+    None
+  }
+
+  /// 次の1歩のコマンド。止まるときはErrでその理由を返す
+  pub fn next_command(&mut self, ecs : &World) -> Result<Command, String> {
+    if let Some(reason) = self.interruption(ecs) {
+      return Err(reason);
+    }
+
+    self.step(ecs)
+  }
+}
@@ -0,0 +1,64 @@
+use super::map_builders::BUILDER_NAMES;
+
+/// コマンドライン引数
+/// 例: cargo run -- --builder bsp --seed 12345
+/// リプレイを再生するとき: cargo run -- --replay last_game.replay
+/// ウィンドウなしで動かすとき: cargo run -- --seed 12345 --headless --script script.txt
+///                          cargo run -- --headless --replay last_game.replay
+pub struct Args {
+  /// 使うマップビルダーの名前。Noneならランダムに選ぶ
+  pub builder : Option<String>,
+  /// 乱数のシード。同じシードなら同じダンジョンになる。Noneならランダムに決める
+  pub seed : Option<u64>,
+  /// ウィンドウを開かずに実行する
+  pub headless : bool,
+  /// headlessで実行するコマンドのスクリプト
+  pub script : Option<String>,
+  /// 再生するリプレイファイル。シードとマップビルダーはファイルに書いてあるものを使う
+  pub replay : Option<String>
+}
+
+pub fn parse_args() -> Result<Args, String> {
+  let mut args = Args{ builder: None, seed: None, headless: false, script: None, replay: None };
+  let mut iter = std::env::args().skip(1);
+
+  while let Some(arg) = iter.next() {
+    match arg.as_str() {
+      "--builder" => {
+        let name = iter.next().ok_or("--builder requires a name")?;
+        if !BUILDER_NAMES.contains(&name.as_str()) {
+          return Err(format!("Unknown builder '{}'. Available: {}", name, BUILDER_NAMES.join(", ")));
+        }
+        args.builder = Some(name);
+      }
+      "--seed" => {
+        let seed = iter.next().ok_or("--seed requires a number")?;
+        args.seed = Some(seed.parse().map_err(|_| format!("Invalid seed '{}'", seed))?);
+      }
+      "--headless" => args.headless = true,
+      "--script" => {
+        args.script = Some(iter.next().ok_or("--script requires a file")?);
+      }
+      "--replay" => {
+        args.replay = Some(iter.next().ok_or("--replay requires a file")?);
+      }
+      _ => return Err(format!("Unknown argument '{}'", arg))
+    }
+  }
+
+  // 組み合わせのチェック
+  if args.replay.is_some() && (args.seed.is_some() || args.builder.is_some()) {
+    return Err("--replay uses the seed and builder recorded in the file; do not pass --seed or --builder".to_string());
+  }
+  if args.script.is_some() && args.replay.is_some() {
+    return Err("--script and --replay cannot be used together".to_string());
+  }
+  if args.script.is_some() && !args.headless {
+    return Err("--script requires --headless".to_string());
+  }
+  if args.headless && args.script.is_none() && args.replay.is_none() {
+    return Err("--headless requires --script or --replay".to_string());
+  }
+
+  Ok(args)
+}
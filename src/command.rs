@@ -0,0 +1,188 @@
+use rltk::Point;
+use super::EquipmentSlot;
+use super::player::MAX_BACKPACK_ITEMS;
+use std::fmt;
+use std::str::FromStr;
+
+/// プレイヤーの行動。キー入力やスクリプトはいったんこれに変換してから実行する。
+/// 画面がなくても同じ行動をとれるように、描画や入力の処理から切り離しておく
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Command {
+  /// 隣のマスへの移動。移動先にモンスターがいれば攻撃
+  Move { delta_x: i32, delta_y: i32 },
+  /// 何もせずにターンを終える
+  Wait,
+  /// 足元のアイテムを拾う
+  PickUp,
+  /// 下り階段を降りる
+  Descend,
+  /// バックパックのslot番目 (メニューのa, b, ...の順) のアイテムを使う。飛び道具ならtargetが要る
+  UseItem { slot: usize, target: Option<Point> },
+  /// バックパックのslot番目のアイテムを置く
+  DropItem { slot: usize },
+  /// slotに装備しているものを外してバックパックに戻す
+  Unequip { slot: EquipmentSlot },
+  /// レベルが上がったときに伸ばすものを選ぶ。ターンは使わない
+  LevelUp { choice: LevelUpChoice }
+}
+
+/// レベルが上がったときに伸ばすもの
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum LevelUpChoice {
+  /// 腕力と近接攻撃の熟練度
+  Might,
+  /// 体力と最大HP
+  Fitness,
+  /// すばやさと防御の熟練度
+  Quickness
+}
+
+impl LevelUpChoice {
+  /// メニューに並べる順
+  pub const ALL : [LevelUpChoice; 3] = [LevelUpChoice::Might, LevelUpChoice::Fitness, LevelUpChoice::Quickness];
+
+  /// スクリプトやリプレイで使う名前
+  pub fn name(&self) -> &'static str {
+    match self {
+      LevelUpChoice::Might => "might",
+      LevelUpChoice::Fitness => "fitness",
+      LevelUpChoice::Quickness => "quickness"
+    }
+  }
+
+  pub fn from_name(name : &str) -> Option<LevelUpChoice> {
+    LevelUpChoice::ALL.iter().copied().find(|choice| choice.name() == name)
+  }
+}
+
+/// 隣の8マスのどれかへの1歩ならtrue。(0, 0)は自分のいるマスなので1歩ではない
+pub fn is_step(delta_x : i32, delta_y : i32) -> bool {
+  (-1..=1).contains(&delta_x) && (-1..=1).contains(&delta_y) && (delta_x, delta_y) != (0, 0)
+}
+
+/// a = 0, b = 1, ... をスロット番号にする
+fn parse_slot(word : Option<&str>) -> Result<usize, String> {
+  let word = word.ok_or("missing item slot")?;
+  let mut chars = word.chars();
+  match (chars.next(), chars.next()) {
+    (Some(c), None) if c.is_ascii_lowercase() => Ok((c as u8 - b'a') as usize),
+    _ => Err(format!("invalid item slot '{}'", word))
+  }
+}
+
+/// スロット番号を a, b, ... に戻す。
+/// バックパックはMAX_BACKPACK_ITEMS (26) までなので、z より後ろにはならない
+fn slot_letter(slot : usize) -> char {
+  debug_assert!(slot < MAX_BACKPACK_ITEMS, "item slot {} has no letter", slot);
+  (b'a' + slot as u8) as char
+}
+
+fn parse_number(word : Option<&str>) -> Result<i32, String> {
+  let word = word.ok_or("missing number")?;
+  word.parse().map_err(|_| format!("invalid number '{}'", word))
+}
+
+/// スクリプトの1行からコマンドを読む。書式は
+/// `move <dx> <dy>`, `wait`, `pickup`, `descend`, `use <slot> [<x> <y>]`, `drop <slot>`, `unequip <melee|shield>`,
+/// `levelup <might|fitness|quickness>`。
+/// slotはインベントリのメニューと同じ a, b, ...
+impl FromStr for Command {
+  type Err = String;
+
+  fn from_str(s : &str) -> Result<Self, Self::Err> {
+    let mut words = s.split_whitespace();
+    let command = match words.next() {
+      Some("move") => {
+        let (delta_x, delta_y) = (parse_number(words.next())?, parse_number(words.next())?);
+        if !is_step(delta_x, delta_y) {
+          return Err(format!("invalid move {} {}, each delta must be -1, 0 or 1 and not both 0", delta_x, delta_y));
+        }
+        Command::Move{ delta_x, delta_y }
+      }
+      Some("wait") => Command::Wait,
+      Some("pickup") => Command::PickUp,
+      Some("descend") => Command::Descend,
+      Some("use") => {
+        let slot = parse_slot(words.next())?;
+        let target = match words.next() {
+          None => None,
+          Some(x) => Some(Point::new(parse_number(Some(x))?, parse_number(words.next())?))
+        };
+        Command::UseItem{ slot, target }
+      }
+      Some("drop") => Command::DropItem{ slot: parse_slot(words.next())? },
+      Some("unequip") => {
+        let name = words.next().ok_or("missing equipment slot")?;
+        let slot = EquipmentSlot::from_name(name).ok_or_else(|| format!("invalid equipment slot '{}'", name))?;
+        Command::Unequip{ slot }
+      }
+      Some("levelup") => {
+        let name = words.next().ok_or("missing level up choice")?;
+        let choice = LevelUpChoice::from_name(name).ok_or_else(|| format!("invalid level up choice '{}'", name))?;
+        Command::LevelUp{ choice }
+      }
+      Some(other) => return Err(format!("unknown command '{}'", other)),
+      None => return Err("empty command".to_string())
+    };
+
+    if let Some(extra) = words.next() {
+      return Err(format!("unexpected '{}' after command", extra));
+    }
+    Ok(command)
+  }
+}
+
+/// FromStrと同じ書式で書き出す。リプレイファイルに残すのに使う
+impl fmt::Display for Command {
+  fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Command::Move { delta_x, delta_y } => write!(f, "move {} {}", delta_x, delta_y),
+      Command::Wait => write!(f, "wait"),
+      Command::PickUp => write!(f, "pickup"),
+      Command::Descend => write!(f, "descend"),
+      Command::UseItem { slot, target: None } => write!(f, "use {}", slot_letter(*slot)),
+      Command::UseItem { slot, target: Some(target) } => write!(f, "use {} {} {}", slot_letter(*slot), target.x, target.y),
+      Command::DropItem { slot } => write!(f, "drop {}", slot_letter(*slot)),
+      Command::Unequip { slot } => write!(f, "unequip {}", slot.name()),
+      Command::LevelUp { choice } => write!(f, "levelup {}", choice.name())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn move_accepts_only_one_step() {
+    assert_eq!("move 1 -1".parse::<Command>(), Ok(Command::Move{ delta_x: 1, delta_y: -1 }));
+    assert!("move 0 0".parse::<Command>().is_err());
+    assert!("move 30 0".parse::<Command>().is_err());
+    assert!("move 0 -2".parse::<Command>().is_err());
+  }
+
+  #[test]
+  fn display_and_from_str_round_trip() {
+    let commands = [
+      Command::Move{ delta_x: -1, delta_y: 0 },
+      Command::Wait,
+      Command::PickUp,
+      Command::Descend,
+      Command::UseItem{ slot: 0, target: None },
+      Command::UseItem{ slot: 2, target: Some(Point::new(10, 12)) },
+      Command::DropItem{ slot: 1 },
+      Command::Unequip{ slot: EquipmentSlot::Shield },
+      Command::LevelUp{ choice: LevelUpChoice::Quickness }
+    ];
+    for command in commands.iter() {
+      assert_eq!(command.to_string().parse::<Command>(), Ok(*command));
+    }
+  }
+
+  #[test]
+  fn malformed_commands_are_rejected() {
+    for line in ["", "jump", "wait now", "use", "use 1", "use a 3", "drop ab", "unequip hat", "levelup luck"].iter() {
+      assert!(line.parse::<Command>().is_err(), "'{}' should not parse", line);
+    }
+  }
+}
@@ -1,70 +1,332 @@
 use specs::prelude::*;
 use specs_derive::*;
+use specs::saveload::{Marker, ConvertSaveload};
+// ConvertSaveloadのderiveがNoErrorという名前を使う。specsのNoErrorは非推奨になったのでInfallibleで置き換える
+use std::convert::Infallible as NoError;
+use serde::{Serialize, Deserialize};
 use rltk::{RGB};
+use super::{Map, GameLog, GameStats};
 
 // PositionがコンポーネントであるとSpecsに伝える
 // #[derive(X)]: Xに必要なお決まりのコードを代わりに書いてくれる
 // 場所
-#[derive(Component)]
+#[derive(Component, Clone, Copy, Serialize, Deserialize)]
 pub struct Position {
     pub x: i32,
     pub y: i32,
 }
 
 // 見た目
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize, Clone)]
 pub struct Renderable {
     pub glyph: rltk::FontCharType,
     pub fg: RGB,
     pub bg: RGB,
+    /// 同じタイルに複数あるときの描画順。小さいほど手前に描く
+    pub render_order: i32,
 }
 
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize, Clone)]
 pub struct Player {}
 
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize, Clone)]
 pub struct Viewshed {
   pub visible_tiles : Vec<rltk::Point>,
   pub range : i32,
   pub dirty : bool
 }
 
-#[derive(Component, Debug)]
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct Monster {}
 
-#[derive(Component, Debug)]
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct Name {
   pub name: String
 }
 
-#[derive(Component, Debug)]
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct BlocksTile {}
 
-#[derive(Component, Debug)]
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
 pub struct CombatStats {
   pub max_hp : i32,
-  pub hp : i32,
-  pub defense : i32,
-  pub power : i32
+  pub hp : i32
+}
+
+/// 能力値。10が人並みで、attr_bonusで判定やダメージに足す値になる
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Attributes {
+  /// 腕力。近接攻撃の命中とダメージに効く
+  pub might : i32,
+  /// 体力。レベルが上がったときに増える最大HPに効く
+  pub fitness : i32,
+  /// すばやさ。攻撃のよけやすさ (アーマークラス) に効く
+  pub quickness : i32,
+  /// 知力。魔法用で、今はまだ何にも効かない
+  pub intelligence : i32
+}
+
+/// 能力値を判定に足す値に変える。10と11で0、12で+1、8で-1
+pub fn attr_bonus(value : i32) -> i32 {
+  (value - 10).div_euclid(2)
+}
+
+/// 技能の熟練度。判定には、関係する能力値のボーナスを足して使う
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Skills {
+  pub melee : i32,
+  pub defense : i32
 }
 
-#[derive(Component, Debug, Clone)]
+impl Skills {
+  /// 近接攻撃の命中に足す値。熟練度 + 腕力のボーナス
+  pub fn melee_bonus(&self, attributes : &Attributes) -> i32 {
+    self.melee + attr_bonus(attributes.might)
+  }
+
+  /// アーマークラスに足す値。熟練度 + すばやさのボーナス
+  pub fn defense_bonus(&self, attributes : &Attributes) -> i32 {
+    self.defense + attr_bonus(attributes.quickness)
+  }
+}
+
+#[derive(Component, Debug, ConvertSaveload, Clone)]
 pub struct WantsToMelee {
     pub target : Entity
 }
 
+/// (ダメージ量, ダメージを与えたエンティティ) のリスト。
+/// DamageSystemがそのターンのうちに消費するので、セーブはしない
 #[derive(Component, Debug)]
 pub struct SufferDamage {
-  pub amount : Vec<i32>
+  pub amount : Vec<(i32, Entity)>
 }
 
 impl SufferDamage {
-  pub fn new_damage(store: &mut WriteStorage<SufferDamage>, victim: Entity, amount: i32) {
+  pub fn new_damage(store: &mut WriteStorage<SufferDamage>, victim: Entity, amount: i32, from: Entity) {
     if let Some(suffering) = store.get_mut(victim) {
-      suffering.amount.push(amount);
+      suffering.amount.push((amount, from));
     } else {
-      let dmg = SufferDamage {amount: vec![amount]};
+      let dmg = SufferDamage {amount: vec![(amount, from)]};
       store.insert(victim, dmg).expect("Unable to insert damage");
     }
   }
-}
\ No newline at end of file
+}
+/// 拾って持ち運べるもの
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Item {}
+
+/// ownerのバックパックに入っている。入っている間はPositionを持たない
+#[derive(Component, Debug, ConvertSaveload, Clone)]
+pub struct InBackpack {
+  pub owner : Entity
+}
+
+#[derive(Component, Debug, ConvertSaveload, Clone)]
+pub struct WantsToPickupItem {
+  pub collected_by : Entity,
+  pub item : Entity
+}
+
+#[derive(Component, Debug, ConvertSaveload, Clone)]
+pub struct WantsToDropItem {
+  pub item : Entity
+}
+
+/// 使うとなくなる
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Consumable {}
+
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct ProvidesHealing {
+  pub heal_amount : i32
+}
+
+/// 離れた相手に使える。rangeはタイル数
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Ranged {
+  pub range : i32
+}
+
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct InflictsDamage {
+  pub damage : i32
+}
+
+/// 狙った地点からradiusの範囲すべてに効果がある
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct AreaOfEffect {
+  pub radius : i32
+}
+
+/// アイテムに付いていれば混乱させる効果、モンスターに付いていれば混乱中 (turnsターン行動できない)
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Confusion {
+  pub turns : i32
+}
+
+/// 経験値とレベル。倒した相手のExperienceRewardが貯まり、次のレベルの経験値に届くとレベルが上がる
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Experience {
+  pub level : i32,
+  pub xp : i32,
+  /// 上がったのに、まだ何を伸ばすか選んでいないレベルの数
+  pub pending_level_ups : i32
+}
+
+impl Experience {
+  /// level + 1 になるのに要る経験値の合計。100, 300, 600, 1000, ... と上がるほど遠くなる
+  pub fn next_level_xp(&self) -> i32 {
+    50 * self.level * (self.level + 1)
+  }
+}
+
+/// 倒すともらえる経験値
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct ExperienceReward {
+  pub xp : i32
+}
+
+/// 装備を付ける場所。1つの場所には1つしか付けられない
+#[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum EquipmentSlot { Melee, Shield }
+
+impl EquipmentSlot {
+  /// キャラクター画面で並べる順
+  pub const ALL : [EquipmentSlot; 2] = [EquipmentSlot::Melee, EquipmentSlot::Shield];
+
+  /// 定義ファイルやコマンドで使う名前
+  pub fn name(&self) -> &'static str {
+    match self {
+      EquipmentSlot::Melee => "melee",
+      EquipmentSlot::Shield => "shield"
+    }
+  }
+
+  pub fn from_name(name : &str) -> Option<EquipmentSlot> {
+    EquipmentSlot::ALL.iter().copied().find(|slot| slot.name() == name)
+  }
+}
+
+/// 装備できるアイテム。使うとslotに装備する
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Equippable {
+  pub slot : EquipmentSlot
+}
+
+/// ownerがslotに装備している。装備している間はバックパックに入っていない
+#[derive(Component, Debug, ConvertSaveload, Clone)]
+pub struct Equipped {
+  pub owner : Entity,
+  pub slot : EquipmentSlot
+}
+
+/// 近接武器。ダメージは damage_n_dice d damage_die_type + damage_bonus。
+/// 装備するアイテムに付くほか、素手で戦うモンスターには本人に付く (牙や爪)
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct MeleeWeapon {
+  pub damage_n_dice : i32,
+  pub damage_die_type : i32,
+  pub damage_bonus : i32,
+  /// 命中の判定に足す値
+  pub hit_bonus : i32
+}
+
+/// 防具。装備するとアーマークラスがdefenseだけ上がる
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct DefenseBonus {
+  pub defense : i32
+}
+
+/// 装備を外してバックパックに戻したい
+#[derive(Component, Debug, ConvertSaveload, Clone)]
+pub struct WantsToRemoveItem {
+  pub item : Entity
+}
+
+/// 行動の順番を決めるためのエネルギー。
+/// energyが0以上になったら行動でき、行動するとその重さだけ減る。時間が進むとspeedずつ貯まる
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Initiative {
+  pub energy : i32,
+  /// 100が普通。200なら普通の者の2倍行動できる
+  pub speed : i32
+}
+
+impl Initiative {
+  /// 行動したぶんのエネルギーを使う
+  pub fn spend(&mut self, cost : i32) {
+    self.energy -= cost;
+  }
+}
+
+/// いま行動する番のエンティティに付く。行動したら外す
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct MyTurn {}
+
+/// モンスターの行動方針
+#[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum AiState {
+  /// 眠っている。近くでプレイヤーを見るか、傷を負うと起きる
+  Idle,
+  /// あてもなくうろつく
+  Wandering,
+  /// プレイヤーを追いかけて攻撃する
+  Chasing,
+  /// 弱ったのでプレイヤーから逃げる
+  Fleeing,
+  /// 見失ったプレイヤーを、最後に見た場所まで行って探す。turnsはあきらめるまでの残り
+  Searching { turns : i32 }
+}
+
+/// モンスターの頭の中。今の行動方針と、最後にプレイヤーを見た場所を覚えておく
+#[derive(Component, Debug, Serialize, Deserialize, Clone)]
+pub struct Brain {
+  pub state : AiState,
+  pub last_seen_player : Option<rltk::Point>,
+  /// 弱ったら逃げるか。falseなら死ぬまで戦う
+  pub flees : bool
+}
+
+/// targetがNoneなら使った本人に効果がある
+#[derive(Component, Debug, ConvertSaveload, Clone)]
+pub struct WantsToUseItem {
+  pub item : Entity,
+  pub target : Option<rltk::Point>
+}
+
+/// セーブ対象のエンティティに付けるマーカー
+pub struct SerializeMe;
+
+/// セーブ時だけ作る、リソースを運ぶためのコンポーネント。
+/// specsのsaveloadはコンポーネントしか保存できないので、ここに詰めて一緒に保存する
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct SerializationHelper {
+  pub map : Map,
+  pub log : GameLog,
+  pub stats : GameStats,
+  pub rng : rltk::RandomNumberGenerator,
+  pub runstate : SavedRunState
+}
+
+/// RunStateのうち、セーブから再開できるもの。
+/// メニューやターゲット選択の途中でセーブした場合は入力待ちから再開する
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SavedRunState { AwaitingInput, PlayerTurn, Ticking }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn next_level_xp_grows_with_level() {
+    let xp : Vec<i32> = (1..=4).map(|level| Experience{ level, xp: 0, pending_level_ups: 0 }.next_level_xp()).collect();
+    assert_eq!(xp, vec![100, 300, 600, 1000]);
+  }
+
+  #[test]
+  fn attr_bonus_rounds_down() {
+    let bonuses : Vec<i32> = [7, 8, 9, 10, 11, 12, 13].iter().map(|value| attr_bonus(*value)).collect();
+    assert_eq!(bonuses, vec![-2, -1, -1, 0, 0, 1, 1]);
+  }
+}
@@ -1,23 +1,83 @@
-use rltk::{console};
+use rltk::RGB;
 use specs::prelude::*;
-use super::{CombatStats, SufferDamage, Player};
+use super::{CombatStats, SufferDamage, Player, Name, GameLog, LogBuilder, GameStats, RunState, Experience, ExperienceReward,
+  Attributes, attr_bonus};
+
+// レベルアップで増える最大HPは2段階に分かれている。
+// まずレベルが上がった時点で、誰でもLEVEL_UP_HP + 体力のボーナス (最低1) だけ増える (このシステム)。
+// そのあとプレイヤーが伸ばすものにFitnessを選ぶと、さらにLEVEL_UP_FITNESS_HPだけ増える (player::apply_level_up)
+
+/// レベルが上がるたびに、選ぶ前から増える最大HP
+const LEVEL_UP_HP : i32 = 5;
+/// レベルアップでFitnessを選んだときに、さらに増える最大HP
+pub const LEVEL_UP_FITNESS_HP : i32 = 5;
 
 pub struct DamageSystem {}
 
 impl<'a> System<'a> for DamageSystem {
+  #[allow(clippy::type_complexity)]
   type SystemData = (
+    Entities<'a>,
     WriteStorage<'a, CombatStats>,
     WriteStorage<'a, SufferDamage>,
+    ReadStorage<'a, Player>,
+    ReadStorage<'a, Name>,
+    WriteExpect<'a, GameStats>,
+    WriteStorage<'a, Experience>,
+    ReadStorage<'a, ExperienceReward>,
+    WriteExpect<'a, GameLog>,
+    ReadStorage<'a, Attributes>
   );
 
   fn run (&mut self, data : Self::SystemData) {
-    let (mut stats, mut damage) = data;
+    let (entities, mut stats, mut damage, players, names, mut game_stats, mut experience, rewards, mut log, attributes) = data;
+
+    // とどめを刺した者と、もらえる経験値
+    let mut xp_gained : Vec<(Entity, i32)> = Vec::new();
 
-    for (mut stats, damage) in (&mut stats, &damage).join() {
-      stats.hp -= damage.amount.iter().sum::<i32>();
+    for (victim, stats, damage) in (&entities, &mut stats, &damage).join() {
+      for (amount, from) in damage.amount.iter() {
+        let was_alive = stats.hp > 0;
+        stats.hp -= amount;
+
+        // このダメージがとどめになったら、誰が倒したかを記録する
+        if was_alive && stats.hp < 1 {
+          if players.get(victim).is_some() {
+            game_stats.killed_by = names.get(*from).map(|name| name.name.clone());
+          } else if players.get(*from).is_some() {
+            game_stats.kills += 1;
+          }
+          if let Some(reward) = rewards.get(victim) {
+            xp_gained.push((*from, reward.xp));
+          }
+        }
+      }
     }
 
     damage.clear();
+
+    for (killer, xp) in xp_gained {
+      let exp = match experience.get_mut(killer) {
+        Some(exp) => exp,
+        None => continue
+      };
+      exp.xp += xp;
+      while exp.xp >= exp.next_level_xp() {
+        exp.level += 1;
+        exp.pending_level_ups += 1;
+        if let Some(stats) = stats.get_mut(killer) {
+          let fitness = attributes.get(killer).map_or(0, |attributes| attr_bonus(attributes.fitness));
+          stats.max_hp += i32::max(1, LEVEL_UP_HP + fitness);
+          stats.hp = stats.max_hp;
+        }
+        if players.get(killer).is_some() {
+          LogBuilder::new()
+            .color(RGB::named(rltk::MAGENTA))
+            .append(format!("Welcome to level {}!", exp.level))
+            .log(&mut log);
+        }
+      }
+    }
   }
 }
 
@@ -28,13 +88,30 @@ pub fn delete_the_dead(ecs : &mut World) {
   {
     let combat_stats = ecs.read_storage::<CombatStats>();
     let players = ecs.read_storage::<Player>();
+    let names = ecs.read_storage::<Name>();
     let entities = ecs.entities();
+    let mut log = ecs.write_resource::<GameLog>();
     for (entity, stats) in (&entities, &combat_stats).join() {
       if stats.hp < 1 {
         let player = players.get(entity);
         match player {
-          None => dead.push(entity),
-          Some(_) => console::log("You are dead")
+          None => {
+            if let Some(victim_name) = names.get(entity) {
+              LogBuilder::new()
+                .npc_name(&victim_name.name)
+                .append(" is dead")
+                .log(&mut log);
+            }
+            dead.push(entity)
+          }
+          Some(_) => {
+            LogBuilder::new()
+              .color(RGB::named(rltk::RED))
+              .append("You are dead")
+              .log(&mut log);
+            let mut runstate = ecs.write_resource::<RunState>();
+            *runstate = RunState::GameOver;
+          }
         }
       }
     }
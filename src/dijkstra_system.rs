@@ -0,0 +1,131 @@
+use specs::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use rltk::{BaseMap, Point};
+use super::{Map, GameLog, DIAGONAL_COST};
+
+/// 逃げるときのマップを作るときに、近づくマップに掛ける数。
+/// 負にして遠いほど小さい値にする。絶対値を1より大きくすると、
+/// 行き止まりに逃げ込むより、プレイヤーの脇をすり抜けて広い方へ逃げるようになる
+const FLEE_FACTOR : f32 = -1.2;
+
+/// 目的地までの距離をタイルごとに持つマップ (Dijkstra map)。
+/// 値の小さい隣のタイルへ動き続ければ目的地に着く。着けないタイルはf32::MAX
+pub type DijkstraValues = Vec<f32>;
+
+/// ターンごとに1回だけ作り、みんなで使うDijkstraマップのリソース
+#[derive(Default)]
+pub struct DijkstraMaps {
+  /// プレイヤーまでの距離。モンスターはこれを下って近づく
+  pub approach_player : DijkstraValues,
+  /// プレイヤーから逃げるためのマップ。これを下って逃げる
+  pub flee_player : DijkstraValues,
+  /// 最後に作ったときの (階層, ターン数, プレイヤーのx, y)
+  computed_for : Option<(i32, i32, i32, i32)>
+}
+
+/// BinaryHeapで値の小さい順に取り出すための要素
+#[derive(PartialEq)]
+struct Node {
+  value : f32,
+  idx : usize
+}
+
+impl Eq for Node {}
+
+impl Ord for Node {
+  fn cmp(&self, other : &Self) -> Ordering {
+    // BinaryHeapは大きい順に出すので逆にする
+    other.value.total_cmp(&self.value).then_with(|| self.idx.cmp(&other.idx))
+  }
+}
+
+impl PartialOrd for Node {
+  fn partial_cmp(&self, other : &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+/// Dijkstraマップを作る。seedsは (タイル, そこでの値) の組で、ふつうは目的地を値0で渡す。
+/// 移動コストはget_available_exitsと同じ (上下左右1.0、ななめDIAGONAL_COST)。
+/// Map::blockedのタイルにも値は付くが、そこを通り抜ける道は作らない
+pub fn build_dijkstra(map : &Map, seeds : &[(usize, f32)]) -> DijkstraValues {
+  let mut values = vec![f32::MAX; map.tiles.len()];
+  let mut open = BinaryHeap::new();
+  for (idx, value) in seeds.iter() {
+    if *value < values[*idx] {
+      values[*idx] = *value;
+      open.push(Node{ value: *value, idx: *idx });
+    }
+  }
+
+  while let Some(Node { value, idx }) = open.pop() {
+    // 先にもっと小さい値で処理済み
+    if value > values[idx] || map.blocked[idx] {
+      continue;
+    }
+    let x = idx as i32 % map.width;
+    let y = idx as i32 / map.width;
+    for delta_y in -1 ..= 1 {
+      for delta_x in -1 ..= 1 {
+        let (nx, ny) = (x + delta_x, y + delta_y);
+        if (delta_x == 0 && delta_y == 0) || nx < 0 || nx >= map.width || ny < 0 || ny >= map.height {
+          continue;
+        }
+        let cost = if delta_x != 0 && delta_y != 0 { DIAGONAL_COST } else { 1.0 };
+        let next = map.xy_idx(nx, ny);
+        if value + cost < values[next] {
+          values[next] = value + cost;
+          open.push(Node{ value: value + cost, idx: next });
+        }
+      }
+    }
+  }
+
+  values
+}
+
+/// idxから動ける隣のタイルのうち、今より値が小さくて一番小さいもの。
+/// 下る先がなければNone (目的地にいるか、道がふさがっている)
+pub fn downhill_step(map : &Map, values : &[f32], idx : usize) -> Option<usize> {
+  map.get_available_exits(idx).iter()
+    .map(|(exit, _)| *exit)
+    .filter(|exit| values[*exit] < values[idx])
+    .min_by(|a, b| values[*a].total_cmp(&values[*b]))
+}
+
+/// プレイヤーの位置からDijkstraマップを作るシステム。
+/// モンスターごとにA*をするかわりに、ターンに1回だけ作ってみんなで使う
+pub struct DijkstraMapSystem {}
+
+impl<'a> System<'a> for DijkstraMapSystem {
+  type SystemData = (
+    ReadExpect<'a, Map>,
+    ReadExpect<'a, Point>,
+    ReadExpect<'a, GameLog>,
+    WriteExpect<'a, DijkstraMaps>
+  );
+
+  fn run(&mut self, data : Self::SystemData) {
+    let (map, player_pos, log, mut dijkstra) = data;
+
+    // ふつうプレイヤーが動くのはターンが進んだときと、階層を移ったときだけ。
+    // 同じターンの中で動いても作り直せるよう、位置もキーに入れておく
+    let key = (map.depth, log.turn, player_pos.x, player_pos.y);
+    if dijkstra.computed_for == Some(key) {
+      return;
+    }
+    dijkstra.computed_for = Some(key);
+
+    let player_idx = map.xy_idx(player_pos.x, player_pos.y);
+    dijkstra.approach_player = build_dijkstra(&map, &[(player_idx, 0.0)]);
+
+    // 近づくマップを裏返して、もう一度広げる。
+    // 値が小さいほどプレイヤーから遠く、しかも逃げ道の多い場所になる
+    let flee_seeds : Vec<(usize, f32)> = dijkstra.approach_player.iter().enumerate()
+      .filter(|(_, value)| **value < f32::MAX)
+      .map(|(idx, value)| (idx, value * FLEE_FACTOR))
+      .collect();
+    dijkstra.flee_player = build_dijkstra(&map, &flee_seeds);
+  }
+}
@@ -0,0 +1,106 @@
+use rltk::RGB;
+use serde::{Serialize, Deserialize};
+
+/// ログ1行の中の、同じ色で描く一続きの文字列
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogFragment {
+  pub color : RGB,
+  pub text : String
+}
+
+/// ログの1行。どのターンの出来事かを持つ
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogEntry {
+  pub turn : i32,
+  pub fragments : Vec<LogFragment>
+}
+
+/// 画面下のログパネルに出すメッセージのリソース。
+/// システムはconsole::logではなく、LogBuilderでメッセージを組み立ててここに積む
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub struct GameLog {
+  pub entries : Vec<LogEntry>,
+  /// 現在のターン数。プレイヤーが行動するたびに増え、エントリのスタンプに使う
+  pub turn : i32,
+  /// 最新の行から何行さかのぼって表示しているか
+  pub scroll : usize
+}
+
+impl GameLog {
+  pub fn new() -> GameLog {
+    GameLog{ entries: Vec::new(), turn: 0, scroll: 0 }
+  }
+
+  pub fn push(&mut self, fragments : Vec<LogFragment>) {
+    self.entries.push(LogEntry{ turn: self.turn, fragments });
+    // 新しいメッセージが来たら最新の行に戻る
+    self.scroll = 0;
+  }
+
+  /// 色付けのいらない一言メッセージ用
+  pub fn log<S : ToString>(&mut self, text : S) {
+    LogBuilder::new().append(text).log(self);
+  }
+
+  /// ログパネルの行数がvisible_linesのとき、linesだけ上下にスクロールする
+  pub fn scroll_up(&mut self, lines : usize, visible_lines : usize) {
+    let max_scroll = self.entries.len().saturating_sub(visible_lines);
+    self.scroll = usize::min(self.scroll + lines, max_scroll);
+  }
+
+  pub fn scroll_down(&mut self, lines : usize) {
+    self.scroll = self.scroll.saturating_sub(lines);
+  }
+}
+
+/// 色付きのログメッセージを組み立てる
+/// 例: LogBuilder::new().npc_name("Orc").append(" hits you").log(&mut gamelog);
+pub struct LogBuilder {
+  current_color : RGB,
+  fragments : Vec<LogFragment>
+}
+
+impl LogBuilder {
+  pub fn new() -> LogBuilder {
+    LogBuilder{ current_color: RGB::named(rltk::WHITE), fragments: Vec::new() }
+  }
+
+  /// これ以降にappendする文字列の色を変える
+  pub fn color(mut self, color : RGB) -> Self {
+    self.current_color = color;
+    self
+  }
+
+  pub fn append<S : ToString>(mut self, text : S) -> Self {
+    self.fragments.push(LogFragment{ color: self.current_color, text: text.to_string() });
+    self
+  }
+
+  /// キャラクター名を目立つ色で足す。色は元に戻す
+  pub fn npc_name<S : ToString>(self, name : S) -> Self {
+    let color = self.current_color;
+    self.color(RGB::named(rltk::YELLOW)).append(name).color(color)
+  }
+
+  /// アイテム名を目立つ色で足す。色は元に戻す
+  pub fn item_name<S : ToString>(self, name : S) -> Self {
+    let color = self.current_color;
+    self.color(RGB::named(rltk::CYAN)).append(name).color(color)
+  }
+
+  /// ダメージ量を赤で足す。色は元に戻す
+  pub fn damage(self, damage : i32) -> Self {
+    let color = self.current_color;
+    self.color(RGB::named(rltk::RED)).append(damage).color(color)
+  }
+
+  pub fn log(self, log : &mut GameLog) {
+    log.push(self.fragments);
+  }
+}
+
+impl Default for LogBuilder {
+  fn default() -> Self {
+    LogBuilder::new()
+  }
+}
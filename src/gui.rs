@@ -0,0 +1,656 @@
+use rltk::{RGB, Rltk, VirtualKeyCode, Point};
+use specs::prelude::*;
+use super::map_builders::BUILDER_NAMES;
+use super::{GameLog, Map, TileType, CombatStats, Player, GameStats, Name, State, Monster, Position, Confusion, Brain, AiState,
+  Equipped, EquipmentSlot, MeleeWeapon, DefenseBonus, Attributes, Skills, attr_bonus, Experience, LevelUpChoice, key_to_direction, backpack_items, targetable_tiles, equipped_item};
+use super::melee_combat_system::{melee_weapon, armor_class};
+use super::damage_system::LEVEL_UP_FITNESS_HP;
+
+/// ログパネルに一度に表示できる行数
+pub const LOG_LINES : usize = 5;
+
+/// マップの下のパネルを描く。
+/// 枠の上辺に階層・HPバー・ターン数、中にログの最新の行を出す
+pub fn draw_ui(ecs : &World, ctx : &mut Rltk) {
+  let map = ecs.fetch::<Map>();
+  let log = ecs.fetch::<GameLog>();
+  let panel_y = map.height;
+  let panel_width = map.width - 1;
+  ctx.draw_box(0, panel_y, panel_width, LOG_LINES as i32 + 1, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK));
+
+  let depth = format!("Depth: {}", map.depth);
+  ctx.print_color(2, panel_y, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), &depth);
+
+  let combat_stats = ecs.read_storage::<CombatStats>();
+  let players = ecs.read_storage::<Player>();
+  for (_player, stats) in (&players, &combat_stats).join() {
+    let health = format!(" HP: {} / {} ", stats.hp, stats.max_hp);
+    ctx.print_color(14, panel_y, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), &health);
+    ctx.draw_bar_horizontal(30, panel_y, 34, stats.hp, stats.max_hp, hp_color(stats.hp, stats.max_hp), RGB::named(rltk::BLACK));
+  }
+
+  let turn = format!("Turn: {}", log.turn);
+  ctx.print_color(panel_width - 1 - turn.len() as i32, panel_y, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), &turn);
+
+  draw_log(&log, ctx, 2, panel_y + 1, LOG_LINES);
+
+  // 枠の下辺の右に、レベルと次のレベルまでの経験値
+  let player_entity = ecs.fetch::<Entity>();
+  if let Some(exp) = ecs.read_storage::<Experience>().get(*player_entity) {
+    let level = format!(" Level {}  XP {} / {} ", exp.level, exp.xp, exp.next_level_xp());
+    ctx.print_color(panel_width - 1 - level.len() as i32, panel_y + LOG_LINES as i32 + 1, RGB::named(rltk::GOLD), RGB::named(rltk::BLACK), &level);
+  }
+
+  draw_tooltips(ecs, ctx);
+}
+
+/// マウスの下のタイルにいるもの全部の名前 (戦えるものはHPも) を枠に入れて出す。
+/// 見えていないタイルでは何も出さない。隠れているモンスターの場所がばれないように
+fn draw_tooltips(ecs : &World, ctx : &mut Rltk) {
+  let map = ecs.fetch::<Map>();
+  let names = ecs.read_storage::<Name>();
+  let combat_stats = ecs.read_storage::<CombatStats>();
+
+  let (mouse_x, mouse_y) = ctx.mouse_pos();
+  if mouse_x < 0 || mouse_x >= map.width || mouse_y < 0 || mouse_y >= map.height {
+    return;
+  }
+  let idx = map.xy_idx(mouse_x, mouse_y);
+  if !map.visible_tiles[idx] {
+    return;
+  }
+
+  let mut lines : Vec<String> = map.tile_content[idx].iter()
+    .filter_map(|entity| {
+      let name = names.get(*entity)?;
+      Some(match combat_stats.get(*entity) {
+        Some(stats) => format!("{} ({}/{})", name.name, stats.hp, stats.max_hp),
+        None => name.name.clone()
+      })
+    })
+    .collect();
+  if lines.is_empty() {
+    return;
+  }
+
+  ctx.set_bg(mouse_x, mouse_y, RGB::named(rltk::MAGENTA));
+
+  // マウスが右半分にあれば左側に、左半分にあれば右側に出して、画面からはみ出さないようにする
+  // 枠は上下で2行使うので、マップの高さに収まる行数までにする
+  lines.truncate((map.height - 2) as usize);
+  let width = lines.iter().map(|line| line.len() as i32).max().unwrap_or(0) + 2;
+  let height = lines.len() as i32 + 1;
+  let x = if mouse_x > map.width / 2 { (mouse_x - width - 2).max(0) } else { mouse_x + 2 };
+  let y = (mouse_y - height / 2).clamp(0, map.height - height - 1);
+  ctx.draw_box(x, y, width, height, RGB::named(rltk::WHITE), RGB::named(rltk::GREY));
+  for (i, line) in lines.iter().enumerate() {
+    ctx.print_color(x + 1, y + 1 + i as i32, RGB::named(rltk::WHITE), RGB::named(rltk::GREY), line);
+  }
+}
+
+/// 残りHPの割合でバーの色を変える
+fn hp_color(hp : i32, max_hp : i32) -> RGB {
+  let ratio = hp as f32 / max_hp as f32;
+  if ratio > 0.5 {
+    RGB::named(rltk::GREEN)
+  } else if ratio > 0.25 {
+    RGB::named(rltk::ORANGE)
+  } else {
+    RGB::named(rltk::RED)
+  }
+}
+
+/// ログの新しい方からlines行を、(x, y)から下に古い順で描く
+fn draw_log(log : &GameLog, ctx : &mut Rltk, x : i32, y : i32, lines : usize) {
+  let end = log.entries.len() - log.scroll;
+  let start = end.saturating_sub(lines);
+
+  for (i, entry) in log.entries[start..end].iter().enumerate() {
+    let mut cx = x;
+    let cy = y + i as i32;
+
+    let stamp = format!("[{:>4}] ", entry.turn);
+    ctx.print_color(cx, cy, RGB::named(rltk::GREY), RGB::named(rltk::BLACK), &stamp);
+    cx += stamp.len() as i32;
+
+    for fragment in entry.fragments.iter() {
+      ctx.print_color(cx, cy, fragment.color, RGB::named(rltk::BLACK), &fragment.text);
+      cx += fragment.text.len() as i32;
+    }
+  }
+
+  // さかのぼって表示中なら目印を出す
+  if log.scroll > 0 {
+    ctx.print_color(x, y + lines as i32, RGB::named(rltk::CYAN), RGB::named(rltk::BLACK), format!("-{}", log.scroll));
+  }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum GameOverResult { NoSelection, NewGame, Quit }
+
+/// 死亡画面。死因、生き延びたターン数、倒した数を出す
+pub fn game_over(ecs : &World, ctx : &mut Rltk) -> GameOverResult {
+  let stats = ecs.fetch::<GameStats>();
+  let log = ecs.fetch::<GameLog>();
+  let map = ecs.fetch::<Map>();
+
+  let killed_by = match &stats.killed_by {
+    Some(name) => format!("You were slain by {}.", name),
+    None => "You died.".to_string()
+  };
+
+  ctx.print_color_centered(15, RGB::named(rltk::RED), RGB::named(rltk::BLACK), "Your journey has ended!");
+  ctx.print_color_centered(18, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), &killed_by);
+  ctx.print_color_centered(20, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), format!("You survived {} turns and reached depth {}.", log.turn, map.depth));
+  ctx.print_color_centered(21, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), format!("You killed {} monsters.", stats.kills));
+
+  ctx.print_color_centered(25, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Press Enter to start a new game, Escape to quit.");
+
+  match ctx.key {
+    Some(VirtualKeyCode::Return) => GameOverResult::NewGame,
+    Some(VirtualKeyCode::Escape) => GameOverResult::Quit,
+    _ => GameOverResult::NoSelection
+  }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum ItemMenuResult { Cancel, NoResponse, Selected }
+
+/// プレイヤーのバックパックの中身を a), b), ... と並べたメニュー。
+/// キーで選んだアイテムを返す。Escapeで閉じる
+pub fn item_menu(gs : &mut State, ctx : &mut Rltk, title : &str) -> (ItemMenuResult, Option<Entity>) {
+  let names = gs.ecs.read_storage::<Name>();
+
+  // コマンドのスロット番号と同じリストから作る。名前がなくても行は飛ばさない (文字がずれる)
+  let items = backpack_items(&gs.ecs);
+  let count = items.len() as i32;
+
+  let y = 25 - (count / 2);
+  ctx.draw_box(15, y - 2, 31, count + 3, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK));
+  ctx.print_color(18, y - 2, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), title);
+  ctx.print_color(18, y + count + 1, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "ESCAPE to cancel");
+
+  for (j, item) in items.iter().enumerate() {
+    let y = y + j as i32;
+    ctx.set(17, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437('('));
+    ctx.set(18, y, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), 97 + j as rltk::FontCharType);
+    ctx.set(19, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437(')'));
+
+    ctx.print(21, y, names.get(*item).map_or("???", |name| name.name.as_str()));
+  }
+
+  match ctx.key {
+    None => (ItemMenuResult::NoResponse, None),
+    Some(VirtualKeyCode::Escape) => (ItemMenuResult::Cancel, None),
+    Some(key) => {
+      // a = 0, b = 1, ...
+      let selection = rltk::letter_to_option(key);
+      if selection > -1 && selection < count {
+        return (ItemMenuResult::Selected, Some(items[selection as usize]));
+      }
+      (ItemMenuResult::NoResponse, None)
+    }
+  }
+}
+
+pub fn show_inventory(gs : &mut State, ctx : &mut Rltk) -> (ItemMenuResult, Option<Entity>) {
+  item_menu(gs, ctx, "Inventory")
+}
+
+pub fn drop_item_menu(gs : &mut State, ctx : &mut Rltk) -> (ItemMenuResult, Option<Entity>) {
+  item_menu(gs, ctx, "Drop which item?")
+}
+
+/// キャラクター画面。能力値、装備込みの命中とアーマークラス、場所ごとの装備を出す。
+/// 装備の行の文字キーでその装備を外す
+pub fn character_screen(gs : &mut State, ctx : &mut Rltk) -> (ItemMenuResult, Option<EquipmentSlot>) {
+  let player_entity = gs.ecs.fetch::<Entity>();
+  let combat_stats = gs.ecs.read_storage::<CombatStats>();
+  let names = gs.ecs.read_storage::<Name>();
+  let equipped = gs.ecs.read_storage::<Equipped>();
+  let weapons = gs.ecs.read_storage::<MeleeWeapon>();
+  let defense_bonuses = gs.ecs.read_storage::<DefenseBonus>();
+  let attributes = gs.ecs.read_storage::<Attributes>();
+  let skills = gs.ecs.read_storage::<Skills>();
+
+  let (stats, attributes, skills) = match (combat_stats.get(*player_entity), attributes.get(*player_entity), skills.get(*player_entity)) {
+    (Some(stats), Some(attributes), Some(skills)) => (stats, attributes, skills),
+    _ => return (ItemMenuResult::Cancel, None)
+  };
+  let weapon = melee_weapon(*player_entity, &equipped, &weapons);
+  let armor_class = armor_class(*player_entity, attributes, skills, &equipped, &defense_bonuses);
+  let damage_bonus = weapon.damage_bonus + attr_bonus(attributes.might);
+
+  let slot_count = EquipmentSlot::ALL.len() as i32;
+  let y = 15;
+  ctx.draw_box(15, y - 2, 40, slot_count + 14, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK));
+  ctx.print_color(18, y - 2, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Character");
+  let level = gs.ecs.read_storage::<Experience>().get(*player_entity).map_or(1, |exp| exp.level);
+  ctx.print_color(30, y - 2, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), format!("Level {}", level));
+  ctx.print(17, y, format!("HP:      {} / {}", stats.hp, stats.max_hp));
+  let scores = [("Might", attributes.might), ("Fitness", attributes.fitness),
+    ("Quickness", attributes.quickness), ("Intelligence", attributes.intelligence)];
+  for (j, (label, value)) in scores.iter().enumerate() {
+    ctx.print(17, y + 1 + j as i32, format!("{:<13}{:>2} ({:+})", label, value, attr_bonus(*value)));
+  }
+  ctx.print(17, y + 6, format!("To hit:      {:+}", skills.melee_bonus(attributes) + weapon.hit_bonus));
+  ctx.print(17, y + 7, format!("Damage:      {}d{}{:+}", weapon.damage_n_dice, weapon.damage_die_type, damage_bonus));
+  ctx.print(17, y + 8, format!("Armor class: {}", armor_class));
+
+  ctx.print_color(17, y + 10, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Equipment");
+  let mut worn = Vec::new();
+  for (j, slot) in EquipmentSlot::ALL.iter().enumerate() {
+    let y = y + 11 + j as i32;
+    let item = equipped_item(&gs.ecs, *slot);
+    ctx.set(17, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437('('));
+    ctx.set(18, y, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), 97 + j as rltk::FontCharType);
+    ctx.set(19, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437(')'));
+    ctx.print(21, y, format!("{:<7}", slot.name()));
+    match item.and_then(|item| names.get(item)) {
+      Some(name) => ctx.print(29, y, &name.name),
+      None => ctx.print_color(29, y, RGB::named(rltk::GREY), RGB::named(rltk::BLACK), "(nothing)")
+    }
+    worn.push(item.is_some());
+  }
+  ctx.print_color(18, y + slot_count + 12, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Letter to unequip, ESCAPE to close");
+
+  match ctx.key {
+    None => (ItemMenuResult::NoResponse, None),
+    Some(VirtualKeyCode::Escape) | Some(VirtualKeyCode::C) => (ItemMenuResult::Cancel, None),
+    Some(key) => {
+      let selection = rltk::letter_to_option(key);
+      if selection > -1 && selection < slot_count && worn[selection as usize] {
+        return (ItemMenuResult::Selected, Some(EquipmentSlot::ALL[selection as usize]));
+      }
+      (ItemMenuResult::NoResponse, None)
+    }
+  }
+}
+
+/// レベルアップで何を伸ばすかを選ぶ。選ぶまで閉じられない
+pub fn level_up_menu(gs : &mut State, ctx : &mut Rltk) -> Option<LevelUpChoice> {
+  let player_entity = gs.ecs.fetch::<Entity>();
+  let level = gs.ecs.read_storage::<Experience>().get(*player_entity).map_or(1, |exp| exp.level);
+  let labels = [
+    "Might: +1 might, +1 melee skill".to_string(),
+    format!("Fitness: +1 fitness, +{} max HP", LEVEL_UP_FITNESS_HP),
+    "Quickness: +1 quickness, +1 defense skill".to_string()
+  ];
+  let count = labels.len() as i32;
+
+  let y = 25 - (count / 2);
+  ctx.draw_box(15, y - 2, 40, count + 3, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK));
+  ctx.print_color(18, y - 2, RGB::named(rltk::MAGENTA), RGB::named(rltk::BLACK), format!("Level {}! Choose a gain", level));
+  for (j, label) in labels.iter().enumerate() {
+    let y = y + j as i32;
+    ctx.set(17, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437('('));
+    ctx.set(18, y, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), 97 + j as rltk::FontCharType);
+    ctx.set(19, y, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK), rltk::to_cp437(')'));
+    ctx.print(21, y, label);
+  }
+
+  let selection = rltk::letter_to_option(ctx.key?);
+  if selection > -1 && selection < count {
+    return Some(LevelUpChoice::ALL[selection as usize]);
+  }
+  None
+}
+
+/// ターゲット選択中のカーソル。マウスが動いたらマウスの位置に合わせる
+pub struct TargetingCursor {
+  pub pos : Point,
+  /// 前のフレームのマウス位置。Noneなら選択を始めたばかり
+  pub last_mouse : Option<Point>
+}
+
+/// 飛び道具の狙いを選ぶ。
+/// 視界内で射程range以内、かつ射線の通るタイルを青く塗り、カーソル (キーボードかマウス) で選ぶ。
+/// Enter/Spaceかクリックで決定、Tabで見えているモンスターを順に選ぶ、Escapeでやめる
+pub fn ranged_target(gs : &mut State, ctx : &mut Rltk, range : i32) -> (ItemMenuResult, Option<Point>) {
+  let player_pos = gs.ecs.fetch::<Point>();
+  let map = gs.ecs.fetch::<Map>();
+  let mut cursor = gs.ecs.write_resource::<TargetingCursor>();
+
+  ctx.print_color(5, 0, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Select Target: Enter to fire, Tab to cycle, Escape to cancel");
+
+  // 狙えるタイルを塗る
+  let available_cells = targetable_tiles(&gs.ecs, range);
+  for idx in available_cells.iter() {
+    ctx.set_bg(idx.x, idx.y, RGB::named(rltk::BLUE));
+  }
+
+  // マウスが動いたらカーソルを合わせる
+  let mouse_pos = ctx.mouse_point();
+  let mouse_moved = matches!(cursor.last_mouse, Some(last) if last != mouse_pos);
+  cursor.last_mouse = Some(mouse_pos);
+  if mouse_moved && mouse_pos.x >= 0 && mouse_pos.x < map.width && mouse_pos.y >= 0 && mouse_pos.y < map.height {
+    cursor.pos = mouse_pos;
+  }
+
+  let valid_target = available_cells.contains(&cursor.pos);
+
+  // 射線とカーソルを描く
+  for point in rltk::line2d(rltk::LineAlg::Bresenham, *player_pos, cursor.pos).iter().skip(1) {
+    ctx.set_bg(point.x, point.y, if valid_target { RGB::named(rltk::DARK_CYAN) } else { RGB::named(rltk::DARK_RED) });
+  }
+  ctx.set_bg(cursor.pos.x, cursor.pos.y, if valid_target { RGB::named(rltk::CYAN) } else { RGB::named(rltk::RED) });
+
+  if ctx.left_click {
+    return if available_cells.contains(&mouse_pos) {
+      (ItemMenuResult::Selected, Some(mouse_pos))
+    } else {
+      (ItemMenuResult::Cancel, None)
+    };
+  }
+
+  match ctx.key {
+    None => {}
+    Some(VirtualKeyCode::Escape) => return (ItemMenuResult::Cancel, None),
+    Some(VirtualKeyCode::Return) | Some(VirtualKeyCode::Space) if valid_target => {
+      return (ItemMenuResult::Selected, Some(cursor.pos));
+    }
+    Some(VirtualKeyCode::Tab) => {
+      let monsters = gs.ecs.read_storage::<Monster>();
+      let positions = gs.ecs.read_storage::<Position>();
+      let targets : Vec<Point> = (&monsters, &positions).join()
+        .map(|(_, pos)| Point::new(pos.x, pos.y))
+        .filter(|pos| available_cells.contains(pos))
+        .collect();
+      if let Some(next) = next_target(*player_pos, targets, cursor.pos) {
+        cursor.pos = next;
+      }
+    }
+    Some(key) => {
+      if let Some((delta_x, delta_y)) = key_to_direction(key) {
+        let new_x = (cursor.pos.x + delta_x).clamp(0, map.width - 1);
+        let new_y = (cursor.pos.y + delta_y).clamp(0, map.height - 1);
+        cursor.pos = Point::new(new_x, new_y);
+      }
+    }
+  }
+
+  (ItemMenuResult::NoResponse, None)
+}
+
+/// targetsを近い順に並べ、currentの次のもの (currentがなければ一番近いもの)。Tabで順に選ぶのに使う
+fn next_target(player_pos : Point, mut targets : Vec<Point>, current : Point) -> Option<Point> {
+  targets.sort_by(|a, b| {
+    let da = rltk::DistanceAlg::Pythagoras.distance2d(player_pos, *a);
+    let db = rltk::DistanceAlg::Pythagoras.distance2d(player_pos, *b);
+    da.partial_cmp(&db).unwrap()
+  });
+  if targets.is_empty() {
+    return None;
+  }
+  let next = match targets.iter().position(|pos| *pos == current) {
+    Some(i) => (i + 1) % targets.len(),
+    None => 0
+  };
+  Some(targets[next])
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum LookResult { Looking, Done }
+
+/// カーソルの下のタイルの説明。地形と、見えていればそこにいるものの名前と様子
+fn describe_tile(ecs : &World, idx : usize) -> Vec<String> {
+  let map = ecs.fetch::<Map>();
+  if !map.revealed_tiles[idx] {
+    return vec!["Unexplored".to_string()];
+  }
+
+  let mut lines = vec![match map.tiles[idx] {
+    TileType::Floor => "Floor",
+    TileType::Wall => "Wall",
+    TileType::DownStairs => "Stairs leading down"
+  }.to_string()];
+  if !map.visible_tiles[idx] {
+    lines.push("You can't see here right now.".to_string());
+    return lines;
+  }
+
+  let names = ecs.read_storage::<Name>();
+  let combat_stats = ecs.read_storage::<CombatStats>();
+  let confusion = ecs.read_storage::<Confusion>();
+  let brains = ecs.read_storage::<Brain>();
+  for entity in map.tile_content[idx].iter() {
+    let name = match names.get(*entity) {
+      Some(name) => name,
+      None => continue
+    };
+    let mut status = Vec::new();
+    if let Some(stats) = combat_stats.get(*entity) {
+      status.push(format!("HP {}/{}", stats.hp, stats.max_hp));
+    }
+    if let Some(brain) = brains.get(*entity) {
+      status.push(match brain.state {
+        AiState::Idle => "asleep",
+        AiState::Wandering => "wandering",
+        AiState::Chasing => "hostile",
+        AiState::Fleeing => "fleeing",
+        AiState::Searching { .. } => "searching"
+      }.to_string());
+    }
+    if confusion.get(*entity).is_some() {
+      status.push("confused".to_string());
+    }
+    if status.is_empty() {
+      lines.push(name.name.clone());
+    } else {
+      lines.push(format!("{} ({})", name.name, status.join(", ")));
+    }
+  }
+  lines
+}
+
+/// キーボードでマップを調べるモード。
+/// 移動キーでカーソルを動かし、その下のタイルの説明を枠に出す。Tabで見えているモンスターを順に選び、Escapeかxで終わる
+pub fn look_around(gs : &mut State, ctx : &mut Rltk) -> LookResult {
+  let player_pos = gs.ecs.fetch::<Point>();
+  let map = gs.ecs.fetch::<Map>();
+  let mut cursor = gs.ecs.write_resource::<TargetingCursor>();
+
+  ctx.print_color(5, 0, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Look: move the cursor, Tab to cycle monsters, Escape to finish");
+  ctx.set_bg(cursor.pos.x, cursor.pos.y, RGB::named(rltk::CYAN));
+
+  // 説明はカーソルと反対側の上の隅に出す
+  let lines = describe_tile(&gs.ecs, map.xy_idx(cursor.pos.x, cursor.pos.y));
+  let width = lines.iter().map(|line| line.len() as i32).max().unwrap_or(0) + 2;
+  let height = lines.len() as i32 + 1;
+  let x = if cursor.pos.x > map.width / 2 { 1 } else { map.width - width - 2 };
+  ctx.draw_box(x, 2, width, height, RGB::named(rltk::WHITE), RGB::named(rltk::BLACK));
+  for (i, line) in lines.iter().enumerate() {
+    let fg = if i == 0 { RGB::named(rltk::YELLOW) } else { RGB::named(rltk::WHITE) };
+    ctx.print_color(x + 1, 3 + i as i32, fg, RGB::named(rltk::BLACK), line);
+  }
+
+  match ctx.key {
+    None => {}
+    Some(VirtualKeyCode::Escape) | Some(VirtualKeyCode::X) => return LookResult::Done,
+    Some(VirtualKeyCode::Tab) => {
+      let monsters = gs.ecs.read_storage::<Monster>();
+      let positions = gs.ecs.read_storage::<Position>();
+      let targets : Vec<Point> = (&monsters, &positions).join()
+        .map(|(_, pos)| Point::new(pos.x, pos.y))
+        .filter(|pos| map.visible_tiles[map.xy_idx(pos.x, pos.y)])
+        .collect();
+      if let Some(next) = next_target(*player_pos, targets, cursor.pos) {
+        cursor.pos = next;
+      }
+    }
+    Some(key) => {
+      if let Some((delta_x, delta_y)) = key_to_direction(key) {
+        let new_x = (cursor.pos.x + delta_x).clamp(0, map.width - 1);
+        let new_y = (cursor.pos.y + delta_y).clamp(0, map.height - 1);
+        cursor.pos = Point::new(new_x, new_y);
+      }
+    }
+  }
+
+  LookResult::Looking
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum MainMenuSelection { NewGame, Continue, Options, Quit }
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum MainMenuResult { NoSelection { selected: MainMenuSelection }, Selected { selected: MainMenuSelection } }
+
+/// メインメニューの項目。上から表示順
+const MAIN_MENU_ENTRIES : [(MainMenuSelection, &str); 4] = [
+  (MainMenuSelection::NewGame, "New Game"),
+  (MainMenuSelection::Continue, "Continue"),
+  (MainMenuSelection::Options, "Options"),
+  (MainMenuSelection::Quit, "Quit"),
+];
+
+/// メニューの1行目のy座標
+const MENU_Y : i32 = 24;
+
+/// 縦に並んだメニューの中で、クリックされた行の番号
+fn clicked_entry(ctx : &Rltk, labels : &[String]) -> Option<usize> {
+  if !ctx.left_click {
+    return None;
+  }
+  let (mouse_x, mouse_y) = ctx.mouse_pos();
+  labels.iter().enumerate()
+    .find(|(i, label)| {
+      let width = label.len() as i32;
+      let x = 40 - width / 2;
+      mouse_y == MENU_Y + *i as i32 && mouse_x >= x && mouse_x < x + width
+    })
+    .map(|(i, _)| i)
+}
+
+/// メニュー項目を中央揃えで並べる。選択中は強調し、選べない項目は灰色にする
+fn draw_menu_entries(ctx : &mut Rltk, labels : &[String], selected : usize, enabled : &[bool]) {
+  for (i, label) in labels.iter().enumerate() {
+    let fg = if !enabled[i] {
+      RGB::named(rltk::GREY)
+    } else if i == selected {
+      RGB::named(rltk::MAGENTA)
+    } else {
+      RGB::named(rltk::WHITE)
+    };
+    ctx.print_color_centered(MENU_Y + i as i32, fg, RGB::named(rltk::BLACK), label);
+  }
+}
+
+/// 上下キーで選択を動かす。選べない項目は飛ばす
+fn step_selection(selected : usize, enabled : &[bool], delta : i32) -> usize {
+  let count = enabled.len() as i32;
+  let mut idx = selected as i32;
+  for _ in 0..count {
+    idx = (idx + delta).rem_euclid(count);
+    if enabled[idx as usize] {
+      return idx as usize;
+    }
+  }
+  selected
+}
+
+/// 起動時やセーブ後に出すメインメニュー。
+/// セーブファイルがなければContinueは灰色にして選べないようにする
+pub fn main_menu(ctx : &mut Rltk, selection : MainMenuSelection, save_exists : bool) -> MainMenuResult {
+  let labels : Vec<String> = MAIN_MENU_ENTRIES.iter().map(|(_, label)| label.to_string()).collect();
+  let enabled : Vec<bool> = MAIN_MENU_ENTRIES.iter()
+    .map(|(entry, _)| *entry != MainMenuSelection::Continue || save_exists)
+    .collect();
+
+  // セーブが消えた直後などで、選べない項目が選ばれたままにならないようにする
+  let mut selected = MAIN_MENU_ENTRIES.iter().position(|(entry, _)| *entry == selection).unwrap();
+  if !enabled[selected] {
+    selected = step_selection(selected, &enabled, 1);
+  }
+
+  ctx.print_color_centered(15, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Rust Roguelike Tutorial");
+  ctx.print_color_centered(19, RGB::named(rltk::GREY), RGB::named(rltk::BLACK), "Use Up/Down and Enter, or click");
+
+  draw_menu_entries(ctx, &labels, selected, &enabled);
+
+  // クリックした項目はそのまま決定
+  if let Some(i) = clicked_entry(ctx, &labels).filter(|i| enabled[*i]) {
+    return MainMenuResult::Selected{ selected: MAIN_MENU_ENTRIES[i].0 };
+  }
+
+  let entry = MAIN_MENU_ENTRIES[selected].0;
+
+  match ctx.key {
+    Some(VirtualKeyCode::Escape) => MainMenuResult::NoSelection{ selected: MainMenuSelection::Quit },
+    Some(VirtualKeyCode::Up) | Some(VirtualKeyCode::K) | Some(VirtualKeyCode::Numpad8) => {
+      MainMenuResult::NoSelection{ selected: MAIN_MENU_ENTRIES[step_selection(selected, &enabled, -1)].0 }
+    }
+    Some(VirtualKeyCode::Down) | Some(VirtualKeyCode::J) | Some(VirtualKeyCode::Numpad2) => {
+      MainMenuResult::NoSelection{ selected: MAIN_MENU_ENTRIES[step_selection(selected, &enabled, 1)].0 }
+    }
+    Some(VirtualKeyCode::Return) => MainMenuResult::Selected{ selected: entry },
+    _ => MainMenuResult::NoSelection{ selected: entry }
+  }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum OptionsMenuSelection { MapGenerator, Back }
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum OptionsMenuResult { NoSelection { selected: OptionsMenuSelection }, Back }
+
+/// マップビルダーの選択肢。Noneは階層ごとにランダム
+fn builder_choices() -> Vec<Option<String>> {
+  std::iter::once(None)
+    .chain(BUILDER_NAMES.iter().map(|name| Some(name.to_string())))
+    .collect()
+}
+
+/// ビルダーの選択肢をdeltaだけ進める
+fn cycle_builder(current : &Option<String>, delta : i32) -> Option<String> {
+  let choices = builder_choices();
+  let idx = choices.iter().position(|choice| choice == current).unwrap_or(0) as i32;
+  choices[(idx + delta).rem_euclid(choices.len() as i32) as usize].clone()
+}
+
+/// オプション画面。ここで選んだ設定は次のNew Gameから使われる
+pub fn options_menu(gs : &mut State, ctx : &mut Rltk, selection : OptionsMenuSelection) -> OptionsMenuResult {
+  let entries = [OptionsMenuSelection::MapGenerator, OptionsMenuSelection::Back];
+  let builder = gs.builder_name.clone().unwrap_or_else(|| "random".to_string());
+  let labels = vec![format!("Map generator: < {} >", builder), "Back".to_string()];
+  let enabled = [true, true];
+
+  ctx.print_color_centered(15, RGB::named(rltk::YELLOW), RGB::named(rltk::BLACK), "Options");
+  ctx.print_color_centered(19, RGB::named(rltk::GREY), RGB::named(rltk::BLACK), "Left/Right to change, Escape to go back");
+
+  let mut selected = entries.iter().position(|entry| *entry == selection).unwrap();
+  draw_menu_entries(ctx, &labels, selected, &enabled);
+
+  // クリックした項目はEnterと同じ扱い
+  let clicked = clicked_entry(ctx, &labels);
+  if let Some(i) = clicked {
+    selected = i;
+  }
+  let entry = entries[selected];
+  if ctx.key == Some(VirtualKeyCode::Return) || clicked.is_some() {
+    match entry {
+      OptionsMenuSelection::MapGenerator => gs.builder_name = cycle_builder(&gs.builder_name, 1),
+      OptionsMenuSelection::Back => return OptionsMenuResult::Back
+    }
+    return OptionsMenuResult::NoSelection{ selected: entry };
+  }
+
+  match ctx.key {
+    Some(VirtualKeyCode::Escape) => OptionsMenuResult::Back,
+    Some(VirtualKeyCode::Up) | Some(VirtualKeyCode::K) | Some(VirtualKeyCode::Numpad8) => {
+      OptionsMenuResult::NoSelection{ selected: entries[step_selection(selected, &enabled, -1)] }
+    }
+    Some(VirtualKeyCode::Down) | Some(VirtualKeyCode::J) | Some(VirtualKeyCode::Numpad2) => {
+      OptionsMenuResult::NoSelection{ selected: entries[step_selection(selected, &enabled, 1)] }
+    }
+    Some(VirtualKeyCode::Left) | Some(VirtualKeyCode::H) | Some(VirtualKeyCode::Numpad4) if entry == OptionsMenuSelection::MapGenerator => {
+      gs.builder_name = cycle_builder(&gs.builder_name, -1);
+      OptionsMenuResult::NoSelection{ selected: entry }
+    }
+    Some(VirtualKeyCode::Right) | Some(VirtualKeyCode::L) | Some(VirtualKeyCode::Numpad6) if entry == OptionsMenuSelection::MapGenerator => {
+      gs.builder_name = cycle_builder(&gs.builder_name, 1);
+      OptionsMenuResult::NoSelection{ selected: entry }
+    }
+    _ => OptionsMenuResult::NoSelection{ selected: entry }
+  }
+}
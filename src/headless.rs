@@ -0,0 +1,440 @@
+use specs::prelude::*;
+use std::fs;
+use std::fmt::Write;
+use super::{State, RunState, Command, GameLog, GameStats, Map, CombatStats, Experience};
+use super::replay::Replay;
+
+/// ウィンドウなしでゲームを動かすランナー。
+/// コマンドを渡すと、プレイヤーの次の入力待ち (か死亡) までターンを進める。
+/// CIなど画面のない環境で、戦闘やAIの動きを確かめるのに使う
+pub struct HeadlessRunner {
+  pub gs : State
+}
+
+impl HeadlessRunner {
+  /// 新しいゲームを作り、最初の入力待ちまで進める
+  pub fn new(seed : u64, builder_name : Option<String>) -> HeadlessRunner {
+    let mut gs = State{ ecs: World::new(), builder_name, seed: None, recorder: None, playback: None, auto_move: None,
+      quit_without_saving: false, save_path: crate::saveload_system::SAVE_PATH.to_string() };
+    gs.new_game(seed);
+    let mut runner = HeadlessRunner{ gs };
+    runner.run_until_input();
+    runner
+  }
+
+  pub fn world(&self) -> &World {
+    &self.gs.ecs
+  }
+
+  pub fn runstate(&self) -> RunState {
+    *self.gs.ecs.fetch::<RunState>()
+  }
+
+  /// 今のターン数
+  pub fn turn(&self) -> i32 {
+    self.gs.ecs.fetch::<GameLog>().turn
+  }
+
+  pub fn is_game_over(&self) -> bool {
+    self.runstate() == RunState::GameOver
+  }
+
+  /// 入力のいらない状態を、入力待ちかゲームオーバーになるまで進める
+  fn run_until_input(&mut self) {
+    loop {
+      let runstate = self.runstate();
+      match runstate {
+        RunState::PreRun | RunState::PlayerTurn | RunState::Ticking | RunState::NextLevel => {
+          let newrunstate = self.gs.advance(runstate);
+          self.gs.apply_runstate(newrunstate);
+        }
+        _ => return
+      }
+    }
+  }
+
+  /// コマンドを1つ実行し、次の入力待ちまで進める。
+  /// 受け付けられたらtrue。実行できないコマンドや、ゲームオーバーの後ならfalse
+  pub fn submit(&mut self, command : Command) -> bool {
+    if !matches!(self.runstate(), RunState::AwaitingInput | RunState::LevelUp) {
+      return false;
+    }
+    // 受け付けられなければ状態は変えない (LevelUpならLevelUpのまま)
+    match self.gs.try_perform(command) {
+      Some(newrunstate) => {
+        self.gs.apply_runstate(newrunstate);
+        self.run_until_input();
+        true
+      }
+      None => false
+    }
+  }
+
+  /// ゲームログ全部と、結果のまとめ。同じゲームなら同じ文字列になる
+  pub fn report(&self) -> String {
+    let ecs = self.world();
+    let log = ecs.fetch::<GameLog>();
+    let stats = ecs.fetch::<GameStats>();
+    let map = ecs.fetch::<Map>();
+    let player_entity = ecs.fetch::<Entity>();
+    let combat_stats = ecs.read_storage::<CombatStats>();
+    // Stringへのwriteln!は失敗しない
+    let mut report = String::new();
+
+    for entry in log.entries.iter() {
+      let text : String = entry.fragments.iter().map(|fragment| fragment.text.as_str()).collect();
+      let _ = writeln!(report, "[{}] {}", entry.turn, text);
+    }
+
+    let _ = writeln!(report, "----");
+    let _ = writeln!(report, "Turns: {}", log.turn);
+    let _ = writeln!(report, "Depth: {}", map.depth);
+    if let Some(player_stats) = combat_stats.get(*player_entity) {
+      let _ = writeln!(report, "HP: {}/{}", player_stats.hp, player_stats.max_hp);
+    }
+    if let Some(exp) = ecs.read_storage::<Experience>().get(*player_entity) {
+      let _ = writeln!(report, "Level: {} ({} xp)", exp.level, exp.xp);
+    }
+    let _ = writeln!(report, "Kills: {}", stats.kills);
+    let _ = match (self.is_game_over(), &stats.killed_by) {
+      (false, _) => writeln!(report, "Result: alive"),
+      (true, Some(name)) => writeln!(report, "Result: slain by {}", name),
+      (true, None) => writeln!(report, "Result: dead")
+    };
+    report
+  }
+
+  /// reportを標準出力に出す
+  pub fn print_report(&self) {
+    print!("{}", self.report());
+  }
+}
+
+/// スクリプトファイルのコマンドを順に実行し、結果を出す。
+/// 1行に1コマンド (書式はCommandのFromStrを参照)、#から後ろはコメント
+pub fn run_script(path : &str, seed : u64, builder_name : Option<String>) -> Result<(), String> {
+  let script = fs::read_to_string(path).map_err(|e| format!("Unable to read script {}: {}", path, e))?;
+
+  // 途中で止まらないよう、先にスクリプト全体を読んでおく
+  let mut commands = Vec::new();
+  for (i, line) in script.lines().enumerate() {
+    let line = line.split('#').next().unwrap().trim();
+    if line.is_empty() {
+      continue;
+    }
+    let command = line.parse::<Command>().map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+    commands.push((i + 1, command));
+  }
+
+  let mut runner = HeadlessRunner::new(seed, builder_name);
+  for (line_number, command) in commands {
+    if runner.is_game_over() {
+      println!("{}:{}: the game is over, skipping the rest of the script", path, line_number);
+      break;
+    }
+    if !runner.submit(command) {
+      println!("{}:{}: '{}' was not accepted", path, line_number, command);
+    }
+  }
+
+  runner.print_report();
+  Ok(())
+}
+
+/// リプレイを再生し、結果を出す。
+/// 記録と同じターンで同じコマンドが受け付けられなければ、再現できなかったとしてエラーにする。
+/// 結果の出力を前のものと比べれば、回帰テストとして使える
+pub fn run_replay(path : &str) -> Result<(), String> {
+  let replay = Replay::load(path)?;
+  let runner = play_replay(path, replay)?;
+  runner.print_report();
+  Ok(())
+}
+
+/// リプレイを最後まで再生したランナーを返す。pathはエラーメッセージ用
+fn play_replay(path : &str, replay : Replay) -> Result<HeadlessRunner, String> {
+  let mut runner = HeadlessRunner::new(replay.seed, replay.builder_name);
+
+  for entry in replay.commands {
+    let desync = |reason : String| format!("{}:{}: replay out of sync: {}", path, entry.line, reason);
+    if runner.is_game_over() {
+      return Err(desync("the game is already over".to_string()));
+    }
+    if runner.turn() != entry.turn {
+      return Err(desync(format!("expected turn {}, game is at turn {}", entry.turn, runner.turn())));
+    }
+    if !runner.submit(entry.command) {
+      return Err(desync(format!("'{}' was not accepted", entry.command)));
+    }
+  }
+  Ok(runner)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::replay::ReplayRecorder;
+  use crate::{Position, Equipped, TileType, Brain, AiState, Viewshed};
+  use crate::map_indexing_system::MapIndexingSystem;
+  use rltk::Point;
+
+  fn runner(seed : u64) -> HeadlessRunner {
+    crate::raws::load_raws().unwrap();
+    HeadlessRunner::new(seed, None)
+  }
+
+  fn player_position(runner : &HeadlessRunner) -> Point {
+    *runner.world().fetch::<Point>()
+  }
+
+  /// プレイヤーを(x, y)に動かす。ターンは進まない
+  fn teleport_player(runner : &mut HeadlessRunner, x : i32, y : i32) {
+    let ecs = &mut runner.gs.ecs;
+    let player_entity = *ecs.fetch::<Entity>();
+    *ecs.write_resource::<Point>() = Point::new(x, y);
+    ecs.write_storage::<Position>().insert(player_entity, Position{ x, y }).unwrap();
+  }
+
+  /// 定義の名前のものをプレイヤーの足元に置く
+  fn spawn_at_player(runner : &mut HeadlessRunner, name : &str) -> Entity {
+    let pos = player_position(runner);
+    crate::raws::raws().spawn_named(&mut runner.gs.ecs, name, pos.x, pos.y, 0, 1).unwrap()
+  }
+
+  fn stairs_position(runner : &HeadlessRunner) -> Point {
+    let map = runner.world().fetch::<Map>();
+    let idx = map.tiles.iter().position(|tile| *tile == TileType::DownStairs).unwrap();
+    Point::new(idx as i32 % map.width, idx as i32 / map.width)
+  }
+
+  /// プレイヤーから見えていて、何もない床のうち、プレイヤーとの距離がdistanceのタイル
+  fn free_visible_floor(runner : &HeadlessRunner, distance : i32) -> Point {
+    let ecs = runner.world();
+    let map = ecs.fetch::<Map>();
+    let player = player_position(runner);
+    let player_entity = *ecs.fetch::<Entity>();
+    let viewsheds = ecs.read_storage::<Viewshed>();
+    viewsheds.get(player_entity).unwrap().visible_tiles.iter().copied()
+      .find(|tile| {
+        let idx = map.xy_idx(tile.x, tile.y);
+        i32::max((tile.x - player.x).abs(), (tile.y - player.y).abs()) == distance
+          && map.tiles[idx] == TileType::Floor && !map.blocked[idx] && map.tile_content[idx].is_empty()
+      })
+      .expect("No free floor in sight")
+  }
+
+  /// 定義の名前のモンスターを(x, y)に置き、起きてうろついている状態にする
+  fn spawn_awake_monster(runner : &mut HeadlessRunner, name : &str, at : Point, flees : bool) -> Entity {
+    let monster = crate::raws::raws().spawn_named(&mut runner.gs.ecs, name, at.x, at.y, 0, 1).unwrap();
+    {
+      let mut brains = runner.gs.ecs.write_storage::<Brain>();
+      let brain = brains.get_mut(monster).unwrap();
+      brain.state = AiState::Wandering;
+      brain.flees = flees;
+    }
+    // 次のターンを待たずに、マップにいることを知らせる
+    MapIndexingSystem{}.run_now(&runner.gs.ecs);
+    monster
+  }
+
+  fn hp(runner : &HeadlessRunner, entity : Entity) -> Option<i32> {
+    runner.world().read_storage::<CombatStats>().get(entity).map(|stats| stats.hp)
+  }
+
+  #[test]
+  fn attacking_a_monster_until_it_dies_gives_a_kill_and_xp() {
+    let mut runner = runner(11);
+    let at = free_visible_floor(&runner, 1);
+    let player = player_position(&runner);
+    let goblin = spawn_awake_monster(&mut runner, "Goblin", at, false);
+
+    let mut swings = 0;
+    while runner.world().entities().is_alive(goblin) && hp(&runner, goblin).unwrap_or(0) > 0 {
+      assert!(swings < 100, "the goblin never died");
+      assert!(runner.submit(Command::Move{ delta_x: at.x - player.x, delta_y: at.y - player.y }));
+      swings += 1;
+    }
+    assert!(!runner.is_game_over());
+    assert_eq!(player_position(&runner), player);
+    assert_eq!(runner.world().fetch::<GameStats>().kills, 1);
+    let report = runner.report();
+    assert!(report.contains("Player hits Goblin #0") || report.contains("Player critically hits Goblin #0"), "{}", report);
+    let player_entity = *runner.world().fetch::<Entity>();
+    assert_eq!(runner.world().read_storage::<Experience>().get(player_entity).unwrap().xp, 30);
+  }
+
+  #[test]
+  fn an_awake_monster_that_sees_the_player_comes_and_attacks() {
+    let mut runner = runner(11);
+    let at = free_visible_floor(&runner, 3);
+    let orc = spawn_awake_monster(&mut runner, "Orc", at, false);
+
+    for _ in 0..20 {
+      assert!(runner.submit(Command::Wait));
+      if runner.report().contains("Orc #0 hits Player") || runner.report().contains("Orc #0 critically hits Player") {
+        break;
+      }
+    }
+    let report = runner.report();
+    assert!(report.contains("Orc #0"), "the orc never attacked: {}", report);
+    assert_eq!(runner.world().read_storage::<Brain>().get(orc).unwrap().state, AiState::Chasing);
+    let player_entity = *runner.world().fetch::<Entity>();
+    assert!(hp(&runner, player_entity).unwrap() < 30, "{}", report);
+  }
+
+  #[test]
+  fn no_monster_starts_next_to_the_player() {
+    crate::raws::load_raws().unwrap();
+    for builder in crate::map_builders::BUILDER_NAMES {
+      for seed in 1 ..= 10 {
+        let runner = HeadlessRunner::new(seed, Some(builder.to_string()));
+        let ecs = runner.world();
+        let map = ecs.fetch::<Map>();
+        let start = map.xy_idx(player_position(&runner).x, player_position(&runner).y);
+        let safe_tiles = match map.rooms.first() {
+          Some(room) => crate::spawner::room_tiles(&map, room).into_iter().collect(),
+          None => crate::spawner::tiles_within(&map, start, crate::spawner::START_SAFE_RADIUS)
+        };
+        let positions = ecs.read_storage::<Position>();
+        let brains = ecs.read_storage::<Brain>();
+        for (pos, _) in (&positions, &brains).join() {
+          assert!(!safe_tiles.contains(&map.xy_idx(pos.x, pos.y)), "{} seed {}: a monster starts at ({}, {})", builder, seed, pos.x, pos.y);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn moves_that_are_not_one_step_are_rejected() {
+    let mut runner = runner(3);
+    let start = player_position(&runner);
+    let turn = runner.turn();
+    assert!(!runner.submit(Command::Move{ delta_x: 0, delta_y: 0 }));
+    assert!(!runner.submit(Command::Move{ delta_x: 30, delta_y: 0 }));
+    assert_eq!(player_position(&runner), start);
+    assert_eq!(runner.turn(), turn);
+    assert!(!runner.report().contains("Player hits Player"));
+  }
+
+  #[test]
+  fn other_commands_do_not_close_the_level_up_choice() {
+    let mut runner = runner(3);
+    let player_entity = *runner.world().fetch::<Entity>();
+    runner.gs.ecs.write_storage::<Experience>().get_mut(player_entity).unwrap().pending_level_ups = 1;
+    runner.gs.apply_runstate(RunState::LevelUp);
+
+    assert!(!runner.submit(Command::Wait));
+    assert!(!runner.submit(Command::Move{ delta_x: 1, delta_y: 0 }));
+    assert!(runner.runstate() == RunState::LevelUp);
+
+    let might = runner.world().read_storage::<crate::Attributes>().get(player_entity).unwrap().might;
+    assert!(runner.submit(Command::LevelUp{ choice: crate::LevelUpChoice::Might }));
+    assert!(runner.runstate() == RunState::AwaitingInput);
+    assert_eq!(runner.world().read_storage::<crate::Attributes>().get(player_entity).unwrap().might, might + 1);
+    assert!(!runner.submit(Command::LevelUp{ choice: crate::LevelUpChoice::Might }));
+  }
+
+  #[test]
+  fn the_backpack_holds_at_most_26_items() {
+    let mut runner = runner(3);
+    spawn_at_player(&mut runner, "Dagger");
+    assert!(runner.submit(Command::PickUp));
+    assert!(runner.submit(Command::UseItem{ slot: 0, target: None }));
+    for _ in 0..crate::player::MAX_BACKPACK_ITEMS {
+      spawn_at_player(&mut runner, "Health Potion");
+      assert!(runner.submit(Command::PickUp));
+    }
+    spawn_at_player(&mut runner, "Health Potion");
+    assert!(!runner.submit(Command::PickUp));
+    assert!(!runner.submit(Command::Unequip{ slot: crate::EquipmentSlot::Melee }));
+    assert!(runner.report().contains("Your backpack is full."));
+    assert_eq!(crate::player::backpack_items(runner.world()).len(), crate::player::MAX_BACKPACK_ITEMS);
+  }
+
+  #[test]
+  fn saving_and_loading_restores_the_game() {
+    let mut runner = runner(5);
+    spawn_at_player(&mut runner, "Dagger");
+    assert!(runner.submit(Command::PickUp));
+    assert!(runner.submit(Command::UseItem{ slot: 0, target: None }));
+    assert!(runner.submit(Command::Wait));
+    let report = runner.report();
+
+    let save_path = std::env::temp_dir().join(format!("rlrust_save_{}.json", std::process::id()));
+    runner.gs.save_path = save_path.to_string_lossy().into_owned();
+    crate::saveload_system::save_game(&mut runner.gs.ecs, &runner.gs.save_path).unwrap();
+    let runstate = runner.gs.continue_game();
+    runner.gs.apply_runstate(runstate);
+    runner.run_until_input();
+    // 読み込んだセーブは消える
+    assert!(!save_path.exists());
+
+    assert_eq!(runner.report(), report);
+    let player_entity = *runner.world().fetch::<Entity>();
+    let equipped = runner.world().read_storage::<Equipped>();
+    assert!(equipped.join().any(|item| item.owner == player_entity));
+  }
+
+  #[test]
+  fn a_corrupt_save_is_set_aside_and_a_new_game_starts() {
+    let mut runner = runner(5);
+    let save_path = std::env::temp_dir().join(format!("rlrust_bad_save_{}.json", std::process::id()));
+    std::fs::write(&save_path, "not a save file").unwrap();
+    runner.gs.save_path = save_path.to_string_lossy().into_owned();
+
+    let runstate = runner.gs.continue_game();
+    runner.gs.apply_runstate(runstate);
+    runner.run_until_input();
+
+    let bad_path = format!("{}.bad", runner.gs.save_path);
+    assert!(!save_path.exists());
+    assert!(std::path::Path::new(&bad_path).exists());
+    std::fs::remove_file(&bad_path).unwrap();
+    assert_eq!(runner.world().fetch::<Map>().depth, 1);
+  }
+
+  #[test]
+  fn equipped_items_survive_descending() {
+    let mut runner = runner(3);
+    let dagger = spawn_at_player(&mut runner, "Dagger");
+    assert!(runner.submit(Command::PickUp));
+    assert!(runner.submit(Command::UseItem{ slot: 0, target: None }));
+    let player_entity = *runner.world().fetch::<Entity>();
+    assert_eq!(runner.world().read_storage::<Equipped>().get(dagger).map(|item| item.owner), Some(player_entity));
+
+    let stairs = stairs_position(&runner);
+    teleport_player(&mut runner, stairs.x, stairs.y);
+    assert!(runner.submit(Command::Descend));
+    assert_eq!(runner.world().fetch::<Map>().depth, 2);
+    assert!(runner.world().entities().is_alive(dagger));
+    assert_eq!(runner.world().read_storage::<Equipped>().get(dagger).map(|item| item.owner), Some(player_entity));
+  }
+
+  #[test]
+  fn replaying_a_recorded_session_gives_the_same_report() {
+    let path = std::env::temp_dir().join(format!("rlrust_record_{}.replay", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let mut original = runner(7);
+    original.gs.recorder = Some(ReplayRecorder::create(path, 7, &None).unwrap());
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, -1)];
+    let mut accepted = 0;
+    for i in 0..120 {
+      let (delta_x, delta_y) = directions[(i / 5) % directions.len()];
+      let command = if i % 7 == 0 { Command::Wait } else { Command::Move{ delta_x, delta_y } };
+      if original.is_game_over() {
+        break;
+      }
+      if original.submit(command) {
+        accepted += 1;
+      }
+    }
+    original.gs.recorder = None;
+
+    let replay = Replay::load(path).unwrap();
+    assert_eq!(replay.commands.len(), accepted);
+    let replayed = play_replay(path, replay).unwrap();
+    let _ = fs::remove_file(path);
+    assert_eq!(replayed.report(), original.report());
+  }
+}
@@ -0,0 +1,48 @@
+use specs::prelude::*;
+use super::{Initiative, MyTurn, RunState};
+
+// 行動の重さ。speed 100の者は、重さ100の行動を時間1単位に1回できる
+pub const MOVE_COST : i32 = 100;
+pub const ATTACK_COST : i32 = 120;
+pub const WAIT_COST : i32 = 100;
+pub const USE_ITEM_COST : i32 = 100;
+/// 拾う・置くは軽い
+pub const PICKUP_COST : i32 = 50;
+
+/// 次に誰が行動するかを決めるスケジューラ。
+/// エネルギーが0以上の者がいなければ、誰かが0に届くまで時間を進めてから、行動できる者全員にMyTurnを付ける
+pub struct InitiativeSystem {}
+
+impl<'a> System<'a> for InitiativeSystem {
+  type SystemData = (
+    Entities<'a>,
+    WriteStorage<'a, Initiative>,
+    WriteStorage<'a, MyTurn>,
+    ReadExpect<'a, RunState>
+  );
+
+  fn run(&mut self, data : Self::SystemData) {
+    let (entities, mut initiatives, mut turns, runstate) = data;
+
+    if *runstate != RunState::Ticking { return; }
+
+    // 一番早くエネルギーが0に届く者に合わせて、全員の時間を進める
+    let wait = (&initiatives).join()
+      .filter(|initiative| initiative.speed > 0)
+      .map(|initiative| if initiative.energy >= 0 { 0 } else { (-initiative.energy + initiative.speed - 1) / initiative.speed })
+      .min();
+    let wait = match wait {
+      Some(wait) => wait,
+      None => return
+    };
+    for initiative in (&mut initiatives).join() {
+      initiative.energy += initiative.speed * wait;
+    }
+
+    for (entity, initiative) in (&entities, &initiatives).join() {
+      if initiative.energy >= 0 {
+        turns.insert(entity, MyTurn{}).expect("Unable to insert turn");
+      }
+    }
+  }
+}
@@ -0,0 +1,269 @@
+use specs::prelude::*;
+use super::{WantsToPickupItem, WantsToDropItem, WantsToUseItem, Name, InBackpack, Position, GameLog, LogBuilder,
+  Map, CombatStats, Consumable, ProvidesHealing, InflictsDamage, AreaOfEffect, Confusion, SufferDamage,
+  Equippable, Equipped, WantsToRemoveItem};
+
+/// 拾いたいアイテムをマップから取り除き、バックパックに入れる
+pub struct ItemCollectionSystem {}
+
+impl<'a> System<'a> for ItemCollectionSystem {
+  #[allow(clippy::type_complexity)]
+  type SystemData = (
+    ReadExpect<'a, Entity>,
+    WriteExpect<'a, GameLog>,
+    WriteStorage<'a, WantsToPickupItem>,
+    WriteStorage<'a, Position>,
+    ReadStorage<'a, Name>,
+    WriteStorage<'a, InBackpack>
+  );
+
+  fn run(&mut self, data : Self::SystemData) {
+    let (player_entity, mut gamelog, mut wants_pickup, mut positions, names, mut backpack) = data;
+
+    for pickup in wants_pickup.join() {
+      positions.remove(pickup.item);
+      backpack.insert(pickup.item, InBackpack{ owner: pickup.collected_by }).expect("Unable to insert backpack entry");
+
+      if pickup.collected_by == *player_entity {
+        LogBuilder::new()
+          .append("You pick up the ")
+          .item_name(&names.get(pickup.item).unwrap().name)
+          .append(".")
+          .log(&mut gamelog);
+      }
+    }
+
+    wants_pickup.clear();
+  }
+}
+
+/// 置きたいアイテムをバックパックから出し、持ち主の足元に置く
+pub struct ItemDropSystem {}
+
+impl<'a> System<'a> for ItemDropSystem {
+  #[allow(clippy::type_complexity)]
+  type SystemData = (
+    ReadExpect<'a, Entity>,
+    WriteExpect<'a, GameLog>,
+    Entities<'a>,
+    WriteStorage<'a, WantsToDropItem>,
+    ReadStorage<'a, Name>,
+    WriteStorage<'a, Position>,
+    WriteStorage<'a, InBackpack>
+  );
+
+  fn run(&mut self, data : Self::SystemData) {
+    let (player_entity, mut gamelog, entities, mut wants_drop, names, mut positions, mut backpack) = data;
+
+    for (entity, to_drop) in (&entities, &wants_drop).join() {
+      let dropper_pos = match positions.get(entity) {
+        Some(pos) => *pos,
+        None => continue
+      };
+      positions.insert(to_drop.item, dropper_pos).expect("Unable to insert position");
+      backpack.remove(to_drop.item);
+
+      if entity == *player_entity {
+        LogBuilder::new()
+          .append("You drop the ")
+          .item_name(&names.get(to_drop.item).unwrap().name)
+          .append(".")
+          .log(&mut gamelog);
+      }
+    }
+
+    wants_drop.clear();
+  }
+}
+
+/// 装備を外してバックパックに戻す
+pub struct ItemRemoveSystem {}
+
+impl<'a> System<'a> for ItemRemoveSystem {
+  #[allow(clippy::type_complexity)]
+  type SystemData = (
+    ReadExpect<'a, Entity>,
+    WriteExpect<'a, GameLog>,
+    Entities<'a>,
+    WriteStorage<'a, WantsToRemoveItem>,
+    ReadStorage<'a, Name>,
+    WriteStorage<'a, Equipped>,
+    WriteStorage<'a, InBackpack>
+  );
+
+  fn run(&mut self, data : Self::SystemData) {
+    let (player_entity, mut gamelog, entities, mut wants_remove, names, mut equipped, mut backpack) = data;
+
+    for (entity, to_remove) in (&entities, &wants_remove).join() {
+      equipped.remove(to_remove.item);
+      backpack.insert(to_remove.item, InBackpack{ owner: entity }).expect("Unable to insert backpack entry");
+
+      if entity == *player_entity {
+        LogBuilder::new()
+          .append("You unequip the ")
+          .item_name(&names.get(to_remove.item).unwrap().name)
+          .append(".")
+          .log(&mut gamelog);
+      }
+    }
+
+    wants_remove.clear();
+  }
+}
+
+/// アイテムを使う。装備品なら装備し、そうでなければ効果の対象を決め、回復・ダメージ・混乱を与え、消耗品なら消す
+pub struct ItemUseSystem {}
+
+impl<'a> System<'a> for ItemUseSystem {
+  #[allow(clippy::type_complexity)]
+  type SystemData = (
+    ReadExpect<'a, Entity>,
+    WriteExpect<'a, GameLog>,
+    ReadExpect<'a, Map>,
+    Entities<'a>,
+    WriteStorage<'a, WantsToUseItem>,
+    ReadStorage<'a, Name>,
+    ReadStorage<'a, Consumable>,
+    ReadStorage<'a, ProvidesHealing>,
+    ReadStorage<'a, InflictsDamage>,
+    ReadStorage<'a, AreaOfEffect>,
+    WriteStorage<'a, Confusion>,
+    WriteStorage<'a, CombatStats>,
+    WriteStorage<'a, SufferDamage>,
+    ReadStorage<'a, Equippable>,
+    WriteStorage<'a, Equipped>,
+    WriteStorage<'a, InBackpack>
+  );
+
+  fn run(&mut self, data : Self::SystemData) {
+    let (player_entity, mut gamelog, map, entities, mut wants_use, names, consumables, healing,
+      inflict_damage, aoe, mut confused, mut combat_stats, mut suffer_damage, equippable, mut equipped, mut backpack) = data;
+
+    for (entity, useitem) in (&entities, &wants_use).join() {
+      let item_name = &names.get(useitem.item).unwrap().name;
+
+      // 装備品。同じ場所に付けているものがあれば外してバックパックに戻してから付ける
+      if let Some(can_equip) = equippable.get(useitem.item) {
+        let slot = can_equip.slot;
+        let to_unequip : Vec<Entity> = (&entities, &equipped).join()
+          .filter(|(_, already)| already.owner == entity && already.slot == slot)
+          .map(|(item, _)| item)
+          .collect();
+        for item in to_unequip {
+          equipped.remove(item);
+          backpack.insert(item, InBackpack{ owner: entity }).expect("Unable to insert backpack entry");
+          if entity == *player_entity {
+            LogBuilder::new()
+              .append("You unequip the ")
+              .item_name(&names.get(item).unwrap().name)
+              .append(".")
+              .log(&mut gamelog);
+          }
+        }
+
+        equipped.insert(useitem.item, Equipped{ owner: entity, slot }).expect("Unable to insert equipped component");
+        backpack.remove(useitem.item);
+        if entity == *player_entity {
+          LogBuilder::new()
+            .append("You equip the ")
+            .item_name(item_name)
+            .append(".")
+            .log(&mut gamelog);
+        }
+        continue;
+      }
+
+      // 効果の対象を集める
+      let mut targets : Vec<Entity> = Vec::new();
+      match useitem.target {
+        None => targets.push(entity),
+        Some(target) => {
+          match aoe.get(useitem.item) {
+            None => {
+              let idx = map.xy_idx(target.x, target.y);
+              targets.extend(map.tile_content[idx].iter());
+            }
+            Some(area_effect) => {
+              // 壁の向こうには届かない
+              let mut blast_tiles = rltk::field_of_view(target, area_effect.radius, &*map);
+              blast_tiles.retain(|p| p.x > 0 && p.x < map.width - 1 && p.y > 0 && p.y < map.height - 1);
+              for tile in blast_tiles.iter() {
+                let idx = map.xy_idx(tile.x, tile.y);
+                targets.extend(map.tile_content[idx].iter());
+              }
+            }
+          }
+        }
+      }
+
+      let mut used_item = false;
+
+      if let Some(heal) = healing.get(useitem.item) {
+        for target in targets.iter() {
+          if let Some(stats) = combat_stats.get_mut(*target) {
+            stats.hp = i32::min(stats.max_hp, stats.hp + heal.heal_amount);
+            if entity == *player_entity {
+              LogBuilder::new()
+                .append("You use the ")
+                .item_name(item_name)
+                .append(", healing ")
+                .color(rltk::RGB::named(rltk::GREEN))
+                .append(heal.heal_amount)
+                .color(rltk::RGB::named(rltk::WHITE))
+                .append(" hp.")
+                .log(&mut gamelog);
+            }
+            used_item = true;
+          }
+        }
+      }
+
+      if let Some(damage) = inflict_damage.get(useitem.item) {
+        for mob in targets.iter() {
+          if combat_stats.get(*mob).is_none() { continue; }
+          SufferDamage::new_damage(&mut suffer_damage, *mob, damage.damage, entity);
+          if entity == *player_entity {
+            LogBuilder::new()
+              .append("You use ")
+              .item_name(item_name)
+              .append(" on ")
+              .npc_name(&names.get(*mob).unwrap().name)
+              .append(", inflicting ")
+              .damage(damage.damage)
+              .append(" hp.")
+              .log(&mut gamelog);
+          }
+          used_item = true;
+        }
+      }
+
+      let confusion_turns = confused.get(useitem.item).map(|confusion| confusion.turns);
+      if let Some(turns) = confusion_turns {
+        for mob in targets.iter() {
+          if combat_stats.get(*mob).is_none() { continue; }
+          confused.insert(*mob, Confusion{ turns }).expect("Unable to insert status");
+          if entity == *player_entity {
+            LogBuilder::new()
+              .append("You use ")
+              .item_name(item_name)
+              .append(" on ")
+              .npc_name(&names.get(*mob).unwrap().name)
+              .append(", confusing them.")
+              .log(&mut gamelog);
+          }
+          used_item = true;
+        }
+      }
+
+      if used_item {
+        if consumables.get(useitem.item).is_some() {
+          entities.delete(useitem.item).expect("Delete failed");
+        }
+      } else if entity == *player_entity {
+        gamelog.log("Nothing happens.");
+      }
+    }
+
+    wants_use.clear();
+  }
+}
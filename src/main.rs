@@ -1,7 +1,9 @@
 // rltkという名前空間から使う
 // Rltk, GameStateという型
-use rltk::{Rltk, GameState, RGB, Point};
+use rltk::{Rltk, GameState, Point};
 use specs::prelude::*;
+use specs::saveload::{SimpleMarker, SimpleMarkerAllocator};
+use serde::{Serialize, Deserialize};
 
 mod components;
 pub use components::*;
@@ -22,14 +24,73 @@ mod monster_ai_system;
 use monster_ai_system::MonsterAI;
 mod map_indexing_system;
 use map_indexing_system::MapIndexingSystem;
+mod dijkstra_system;
+use dijkstra_system::{DijkstraMapSystem, DijkstraMaps};
 mod melee_combat_system;
 use melee_combat_system::MeleeCombatSystem;
 mod damage_system;
 use damage_system::DamageSystem;
+mod map_builders;
+mod spawner;
+mod cli;
+mod gamelog;
+pub use gamelog::*;
+mod gui;
+mod saveload_system;
+mod initiative_system;
+use initiative_system::InitiativeSystem;
+mod inventory_system;
+use inventory_system::{ItemCollectionSystem, ItemDropSystem, ItemUseSystem, ItemRemoveSystem};
+mod command;
+pub use command::{Command, LevelUpChoice};
+mod headless;
+mod replay;
+mod auto_move;
+mod raws;
+mod random_table;
 
 // PartialEq allows you to compare the RunState with other RunState variables to determine if they are the same (or different)
 #[derive(PartialEq, Copy, Clone)]
-pub enum RunState { PreRun, AwaitingInput, PlayerTurn, MonsterTurn }
+pub enum RunState {
+    PreRun,
+    AwaitingInput,
+    PlayerTurn,
+    /// スケジューラが次に行動する者を決め、プレイヤー以外を動かす。プレイヤーの番が来たら入力待ちへ
+    Ticking,
+    ShowInventory,
+    ShowDropItem,
+    /// 強さと装備を見る画面。装備を選ぶと外す
+    ShowCharacter,
+    /// レベルが上がったので、何を伸ばすかを選ぶ。選び終わるまで他の行動はできない
+    LevelUp,
+    /// 飛び道具の狙いを選んでいる。決まったらitemをその地点に使う
+    ShowTargeting { range: i32, item: Entity },
+    /// キーボードのカーソルでマップを調べている。ターンは進まない
+    LookAround,
+    NextLevel,
+    /// セーブしてメインメニューに戻る
+    SaveGame,
+    /// 起動時やセーブ後のメニュー。選択中の項目を持つ
+    MainMenu { menu_selection: gui::MainMenuSelection },
+    /// メインメニューから開くオプション画面
+    OptionsMenu { selection: gui::OptionsMenuSelection },
+    GameOver
+}
+
+impl RunState {
+    /// ゲームを遊んでいる最中ならtrue。メニューや死亡画面ではマップを描かず、セーブもしない
+    fn is_in_game(&self) -> bool {
+        !matches!(self, RunState::MainMenu { .. } | RunState::OptionsMenu { .. } | RunState::GameOver)
+    }
+}
+
+/// 今回のゲームの記録。死亡画面で使う
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub struct GameStats {
+    pub kills: i32,
+    /// プレイヤーにとどめを刺したもののName
+    pub killed_by: Option<String>,
+}
 
 // 構造体をつくる
 // データなりメソッドなりを持たせることができるが、ここではからっぽにして、
@@ -38,6 +99,20 @@ pub enum RunState { PreRun, AwaitingInput, PlayerTurn, MonsterTurn }
 pub struct State {
     pub ecs: World,
     // pub runstate: RunState,
+    /// 使うマップビルダー。コマンドラインかオプション画面で選ぶ。Noneなら階層ごとにランダムに選ぶ
+    pub builder_name: Option<String>,
+    /// コマンドラインで指定されたシード。最初のNew Gameで使う
+    pub seed: Option<u64>,
+    /// 受け付けたコマンドの記録先。Noneなら記録しない
+    pub recorder: Option<replay::ReplayRecorder>,
+    /// 再生中のリプレイ。入力待ちのたびにキー入力の代わりにここからコマンドを取る
+    pub playback: Option<replay::Replay>,
+    /// 自動探索などで、何ターンも続けて自動で歩いている最中ならSome
+    pub auto_move: Option<auto_move::AutoMove>,
+    /// ウィンドウを閉じるときのセーブに失敗した。次に閉じるときはセーブせずに終わる
+    pub quit_without_saving: bool,
+    /// セーブファイルの置き場所。ふだんはsaveload_system::SAVE_PATH
+    pub save_path: String,
 }
 
 // 上のStateでGameStateというトレイトを実装する
@@ -61,34 +136,175 @@ impl GameState for State {
             newrunstate = *runstate;
         }
 
+        // ウィンドウが閉じられたら、遊んでいる途中ならセーブしてから終わる
+        let mut close_requested = false;
+        rltk::INPUT.lock().for_each_message(|event| {
+            if matches!(event, rltk::BEvent::CloseRequested) {
+                close_requested = true;
+            }
+        });
+        // セーブできなかったときは、遊んでいたゲームを失わないよう閉じずに知らせる。もう一度閉じればセーブせずに終わる
+        if close_requested {
+            let saved = if newrunstate.is_in_game() && !self.quit_without_saving {
+                saveload_system::save_game(&mut self.ecs, &self.save_path)
+            } else {
+                Ok(())
+            };
+            match saved {
+                Ok(()) => {
+                    ctx.quit();
+                    return;
+                }
+                Err(e) => {
+                    LogBuilder::new()
+                        .color(rltk::RGB::named(rltk::RED))
+                        .append(format!("{}. Close the window again to quit without saving.", e))
+                        .log(&mut self.ecs.write_resource::<GameLog>());
+                    self.quit_without_saving = true;
+                }
+            }
+        }
+
+        // 先にマップとパネルを描き、メニューなどはその上に重ねる
+        if newrunstate.is_in_game() {
+            self.draw_game(ctx);
+        }
+
         match newrunstate {
-            RunState::PreRun => {
-                self.run_systems();
-                newrunstate = RunState::AwaitingInput;
+            RunState::PreRun | RunState::PlayerTurn | RunState::Ticking | RunState::NextLevel => {
+                newrunstate = self.advance(newrunstate);
             }
             RunState::AwaitingInput => {
-                newrunstate = player_input(self, ctx);
+                newrunstate = if self.playback.is_some() {
+                    self.play_back(ctx)
+                } else if self.auto_move.is_some() {
+                    self.continue_auto_move(ctx)
+                } else {
+                    player_input(self, ctx)
+                };
             }
-            RunState::PlayerTurn => {
-                self.run_systems();
-                newrunstate = RunState::MonsterTurn;
+            RunState::ShowInventory => {
+                let (result, item) = gui::show_inventory(self, ctx);
+                match result {
+                    gui::ItemMenuResult::Cancel => newrunstate = RunState::AwaitingInput,
+                    gui::ItemMenuResult::NoResponse => {}
+                    gui::ItemMenuResult::Selected => {
+                        let item_entity = item.unwrap();
+                        newrunstate = self.use_item(item_entity);
+                    }
+                }
             }
-            RunState::MonsterTurn => {
-                self.run_systems();
-                newrunstate = RunState::AwaitingInput;
+            RunState::ShowDropItem => {
+                let (result, item) = gui::drop_item_menu(self, ctx);
+                match result {
+                    gui::ItemMenuResult::Cancel => newrunstate = RunState::AwaitingInput,
+                    gui::ItemMenuResult::NoResponse => {}
+                    gui::ItemMenuResult::Selected => {
+                        let slot = self.backpack_slot(item.unwrap());
+                        newrunstate = self.perform(Command::DropItem{ slot });
+                    }
+                }
+            }
+            RunState::ShowCharacter => {
+                let (result, slot) = gui::character_screen(self, ctx);
+                match result {
+                    gui::ItemMenuResult::Cancel => newrunstate = RunState::AwaitingInput,
+                    gui::ItemMenuResult::NoResponse => {}
+                    gui::ItemMenuResult::Selected => newrunstate = self.perform(Command::Unequip{ slot: slot.unwrap() })
+                }
+            }
+            RunState::LevelUp => {
+                newrunstate = if self.playback.is_some() {
+                    self.play_back(ctx)
+                } else {
+                    match gui::level_up_menu(self, ctx) {
+                        Some(choice) => self.perform(Command::LevelUp{ choice }),
+                        None => RunState::LevelUp
+                    }
+                };
+            }
+            RunState::ShowTargeting { range, item } => {
+                let (result, target) = gui::ranged_target(self, ctx, range);
+                match result {
+                    gui::ItemMenuResult::Cancel => newrunstate = RunState::AwaitingInput,
+                    gui::ItemMenuResult::NoResponse => {}
+                    gui::ItemMenuResult::Selected => {
+                        let slot = self.backpack_slot(item);
+                        newrunstate = self.perform(Command::UseItem{ slot, target });
+                    }
+                }
+            }
+            RunState::LookAround => {
+                if gui::look_around(self, ctx) == gui::LookResult::Done {
+                    newrunstate = RunState::AwaitingInput;
+                }
+            }
+            RunState::SaveGame => {
+                // セーブできなければメニューに戻らず、そのまま遊び続けられるようにする
+                newrunstate = match saveload_system::save_game(&mut self.ecs, &self.save_path) {
+                    Ok(()) => {
+                        self.quit_without_saving = false;
+                        RunState::MainMenu{ menu_selection: gui::MainMenuSelection::Continue }
+                    }
+                    Err(e) => {
+                        LogBuilder::new()
+                            .color(rltk::RGB::named(rltk::RED))
+                            .append(e)
+                            .log(&mut self.ecs.write_resource::<GameLog>());
+                        RunState::AwaitingInput
+                    }
+                };
+            }
+            RunState::MainMenu { menu_selection } => {
+                let save_exists = saveload_system::does_save_exist(&self.save_path);
+                match gui::main_menu(ctx, menu_selection, save_exists) {
+                    gui::MainMenuResult::NoSelection { selected } => newrunstate = RunState::MainMenu{ menu_selection: selected },
+                    gui::MainMenuResult::Selected { selected } => match selected {
+                        gui::MainMenuSelection::NewGame => {
+                            let seed = self.seed.take().unwrap_or_else(|| rltk::RandomNumberGenerator::new().next_u64());
+                            self.new_game(seed);
+                            self.start_recording(seed);
+                            newrunstate = RunState::PreRun;
+                        }
+                        gui::MainMenuSelection::Continue => newrunstate = self.continue_game(),
+                        gui::MainMenuSelection::Options => newrunstate = RunState::OptionsMenu{ selection: gui::OptionsMenuSelection::MapGenerator },
+                        gui::MainMenuSelection::Quit => ctx.quit()
+                    }
+                }
+            }
+            RunState::OptionsMenu { selection } => {
+                match gui::options_menu(self, ctx, selection) {
+                    gui::OptionsMenuResult::NoSelection { selected } => newrunstate = RunState::OptionsMenu{ selection: selected },
+                    gui::OptionsMenuResult::Back => newrunstate = RunState::MainMenu{ menu_selection: gui::MainMenuSelection::Options }
+                }
+            }
+            RunState::GameOver => {
+                // 死亡画面だけを描き、ターンは進めない
+                match gui::game_over(&self.ecs, ctx) {
+                    gui::GameOverResult::NoSelection => {}
+                    gui::GameOverResult::NewGame => {
+                        // 次のゲームのシードも今のRNGから引くので、最初のシードから再現できる
+                        let seed = self.ecs.write_resource::<rltk::RandomNumberGenerator>().next_u64();
+                        self.new_game(seed);
+                        self.start_recording(seed);
+                    }
+                    gui::GameOverResult::Quit => ctx.quit()
+                }
+                return;
             }
         }
 
-        // newrunstateをリソースのRunStateに反映
-        {
-            let mut runwriter = self.ecs.write_resource::<RunState>();
-            *runwriter = newrunstate;
-        }
+        self.apply_runstate(newrunstate);
+    }
+}
 
-        damage_system::delete_the_dead(&mut self.ecs);
-        
+// Stateに機能を実装
+// LeftWalkerシステムを使えるようにするため、Stateに追加
+impl State {
+    /// マップ、見えているエンティティ、下のパネルを描く
+    fn draw_game(&self, ctx : &mut Rltk) {
         draw_map(&self.ecs, ctx);
-        
+
         // 各コンポーネントの保存場所への読み取りアクセス
         let positions = self.ecs.read_storage::<Position>();
         let renderables = self.ecs.read_storage::<Renderable>();
@@ -96,43 +312,416 @@ impl GameState for State {
 
         // join: PositionとRenderable両方のコンポーネントを持つエンティティ (だけ) をすべて返す
         // The join method is passing us both, guaranteed to belong to the same enitity
-        for (pos, render) in (&positions, &renderables).join() {
+        // render_orderの大きいもの (床のアイテムなど) から描いて、モンスターやプレイヤーを上に重ねる
+        let mut data = (&positions, &renderables).join().collect::<Vec<_>>();
+        data.sort_by_key(|&(_, render)| std::cmp::Reverse(render.render_order));
+        for (pos, render) in data.iter() {
             let idx = map.xy_idx(pos.x, pos.y);
             if map.visible_tiles[idx] {
                 ctx.set(pos.x, pos.y, render.fg, render.bg, render.glyph);
             }
         }
+
+        gui::draw_ui(&self.ecs, ctx);
+    }
+
+    /// 入力を待たずに進む状態 (PreRun, PlayerTurn, Ticking, NextLevel) を1段階進め、次の状態を返す。
+    /// 描画も入力も使わないので、ウィンドウがなくても動かせる
+    fn advance(&mut self, runstate: RunState) -> RunState {
+        match runstate {
+            RunState::PreRun => {
+                self.run_systems();
+                RunState::Ticking
+            }
+            RunState::PlayerTurn => {
+                self.ecs.write_resource::<GameLog>().turn += 1;
+                self.run_systems();
+                RunState::Ticking
+            }
+            RunState::Ticking => {
+                self.run_systems();
+                // スケジューラがプレイヤーに番を回したら入力を待つ。そうでなければまだ時間を進める。
+                // 上がったレベルの伸ばし方を選んでいなければ、先にそれを選ばせる
+                let player_entity = *self.ecs.fetch::<Entity>();
+                if self.ecs.read_storage::<MyTurn>().contains(player_entity) {
+                    if pending_level_ups(&self.ecs) > 0 { RunState::LevelUp } else { RunState::AwaitingInput }
+                } else {
+                    RunState::Ticking
+                }
+            }
+            RunState::NextLevel => {
+                self.goto_next_level();
+                RunState::PreRun
+            }
+            other => other
+        }
+    }
+
+    /// newrunstateをリソースのRunStateに反映し、死んだエンティティを片付ける。
+    /// プレイヤーが死んでいればここでGameOverになる
+    fn apply_runstate(&mut self, newrunstate: RunState) {
+        {
+            let mut runwriter = self.ecs.write_resource::<RunState>();
+            *runwriter = newrunstate;
+        }
+
+        damage_system::delete_the_dead(&mut self.ecs);
     }
-}
 
-// Stateに機能を実装
-// LeftWalkerシステムを使えるようにするため、Stateに追加
-impl State {
     // self: Stateのインスタンス
     fn run_systems(&mut self) {
+        let mut initiative = InitiativeSystem{};
+        initiative.run_now(&self.ecs);
+
         let mut vis = VisibilitySystem{};
         vis.run_now(&self.ecs);
 
+        // MonsterAIはDijkstraMapSystemより先に走り、前に作ったマップを使う。
+        // モンスターが動くのはTickingのときだけで、その前のPlayerTurnでプレイヤーが動いた後のマップが作り直されている
         let mut mob = MonsterAI{};
         mob.run_now(&self.ecs);
 
         let mut mapindex = MapIndexingSystem{};
         mapindex.run_now(&self.ecs);
 
+        let mut dijkstra = DijkstraMapSystem{};
+        dijkstra.run_now(&self.ecs);
+
         let mut melee = MeleeCombatSystem{};
         melee.run_now(&self.ecs);
 
+        let mut items = ItemUseSystem{};
+        items.run_now(&self.ecs);
+
         let mut damage = DamageSystem{};
         damage.run_now(&self.ecs);
 
+        let mut pickup = ItemCollectionSystem{};
+        pickup.run_now(&self.ecs);
+
+        let mut drop_items = ItemDropSystem{};
+        drop_items.run_now(&self.ecs);
+
+        let mut remove_items = ItemRemoveSystem{};
+        remove_items.run_now(&self.ecs);
+
         // システムによってなにか変更がなされたら、その変更はすぐ？Worldに適用してください
         self.ecs.maintain();
     }
+
+    /// コマンドを実行して次の状態を返す。
+    /// 受け付けられなかったときは今の状態のまま (レベルアップの選択中ならLevelUpのまま)
+    fn perform(&mut self, command: Command) -> RunState {
+        match self.try_perform(command) {
+            Some(newrunstate) => newrunstate,
+            None => *self.ecs.fetch::<RunState>()
+        }
+    }
+
+    /// コマンドを実行し、受け付けられたら次の状態を返す。受け付けたコマンドはリプレイに記録する
+    fn try_perform(&mut self, command: Command) -> Option<RunState> {
+        let turn = self.ecs.fetch::<GameLog>().turn;
+        // perform_commandは、実行できなかったコマンドにはAwaitingInputを返す
+        let newrunstate = perform_command(&mut self.ecs, command);
+        if newrunstate == RunState::AwaitingInput {
+            return None;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(turn, command) {
+                // 書けなくなったら記録はあきらめて、ゲームは続ける
+                self.ecs.write_resource::<GameLog>().log(format!("Replay recording stopped: {}", e));
+                self.recorder = None;
+            }
+        }
+        Some(newrunstate)
+    }
+
+    /// 新しいゲームの記録を始める。前のゲームの記録は上書きされる
+    fn start_recording(&mut self, seed: u64) {
+        match replay::ReplayRecorder::create(replay::REPLAY_PATH, seed, &self.builder_name) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e) => {
+                self.recorder = None;
+                self.ecs.write_resource::<GameLog>().log(format!("Unable to record replay: {}", e));
+            }
+        }
+    }
+
+    /// リプレイから次のコマンドを1つ実行する。
+    /// 最後まで再生するかEscapeが押されたら再生をやめ、操作をプレイヤーに返す
+    fn play_back(&mut self, ctx: &mut Rltk) -> RunState {
+        let next = match &mut self.playback {
+            Some(replay) if ctx.key != Some(rltk::VirtualKeyCode::Escape) => replay.commands.pop_front(),
+            _ => None
+        };
+        let entry = match next {
+            Some(entry) => entry,
+            None => {
+                self.playback = None;
+                self.ecs.write_resource::<GameLog>().log("Replay finished. You have control.");
+                return RunState::AwaitingInput;
+            }
+        };
+
+        // 記録と違うターンになっていたら、同じゲームを再現できていない
+        let turn = self.ecs.fetch::<GameLog>().turn;
+        if entry.turn != turn {
+            LogBuilder::new()
+                .color(rltk::RGB::named(rltk::RED))
+                .append(format!("Replay out of sync at line {}: expected turn {}, now turn {}", entry.line, entry.turn, turn))
+                .log(&mut self.ecs.write_resource::<GameLog>());
+        }
+        self.perform(entry.command)
+    }
+
+    /// 自動移動を1歩進める。何か起きるか、キーかマウスが押されたら止める
+    fn continue_auto_move(&mut self, ctx: &mut Rltk) -> RunState {
+        let next = match &mut self.auto_move {
+            Some(_) if ctx.key.is_some() || ctx.left_click => Err("Stopped.".to_string()),
+            Some(auto_move) => auto_move.next_command(&self.ecs),
+            None => return RunState::AwaitingInput
+        };
+        match next {
+            Ok(command) => self.perform(command),
+            Err(reason) => {
+                self.auto_move = None;
+                self.ecs.write_resource::<GameLog>().log(reason);
+                RunState::AwaitingInput
+            }
+        }
+    }
+
+    /// バックパックの中でのitemの位置。コマンドはアイテムをこの番号で指す
+    fn backpack_slot(&self, item: Entity) -> usize {
+        backpack_items(&self.ecs).iter().position(|entry| *entry == item).expect("Item is not in the backpack")
+    }
+
+    /// インベントリで選んだアイテムを使う。
+    /// 離れた相手に使うアイテムはターゲット選択に移る。カーソルは見えている一番近いモンスターから始める
+    fn use_item(&mut self, item_entity: Entity) -> RunState {
+        let range = self.ecs.read_storage::<Ranged>().get(item_entity).map(|ranged| ranged.range);
+        if let Some(range) = range {
+            let player_pos = *self.ecs.fetch::<Point>();
+            let start = nearest_visible_target(&self.ecs, range).unwrap_or(player_pos);
+            let mut cursor = self.ecs.write_resource::<gui::TargetingCursor>();
+            cursor.pos = start;
+            cursor.last_mouse = None;
+            return RunState::ShowTargeting{ range, item: item_entity };
+        }
+
+        let slot = self.backpack_slot(item_entity);
+        self.perform(Command::UseItem{ slot, target: None })
+    }
+
+    /// 新しい階層のマップを作ってリソースを差し替え、モンスターを置き、プレイヤーをスタート地点に移す
+    fn generate_world_map(&mut self, new_depth: i32) {
+        let mut builder = {
+            let mut rng = self.ecs.write_resource::<rltk::RandomNumberGenerator>();
+            let mut builder = match &self.builder_name {
+                Some(name) => map_builders::builder_by_name(name, new_depth).unwrap(),
+                None => map_builders::random_builder(new_depth, &mut rng)
+            };
+            builder.build_map(&mut rng);
+            builder
+        };
+        {
+            let mut worldmap_resource = self.ecs.write_resource::<Map>();
+            *worldmap_resource = builder.get_map();
+        }
+        builder.spawn_entities(&mut self.ecs);
+
+        let Position { x: player_x, y: player_y } = builder.get_starting_position();
+        let mut player_position = self.ecs.write_resource::<Point>();
+        *player_position = Point::new(player_x, player_y);
+        let player_entity = self.ecs.fetch::<Entity>();
+        let mut position_components = self.ecs.write_storage::<Position>();
+        if let Some(player_pos_comp) = position_components.get_mut(*player_entity) {
+            player_pos_comp.x = player_x;
+            player_pos_comp.y = player_y;
+        }
+
+        // 新しいマップで視野を計算し直す
+        let mut viewshed_components = self.ecs.write_storage::<Viewshed>();
+        if let Some(vs) = viewshed_components.get_mut(*player_entity) {
+            vs.dirty = true;
+        }
+    }
+
+    /// 次の階層に移るときに消すエンティティ。プレイヤーとその持ち物 (バックパックの中と装備) 以外すべて
+    fn entities_to_remove_on_level_change(&mut self) -> Vec<Entity> {
+        let entities = self.ecs.entities();
+        let player_entity = self.ecs.fetch::<Entity>();
+        let backpack = self.ecs.read_storage::<InBackpack>();
+        let equipped = self.ecs.read_storage::<Equipped>();
+
+        entities.join()
+            .filter(|entity| *entity != *player_entity)
+            .filter(|entity| !matches!(backpack.get(*entity), Some(pack) if pack.owner == *player_entity))
+            .filter(|entity| !matches!(equipped.get(*entity), Some(item) if item.owner == *player_entity))
+            .collect()
+    }
+
+    /// Worldを空の状態に作り直す。コンポーネントを登録し、リソースを初期値で入れる。
+    /// 前のゲームのエンティティやリソースは何も残らない
+    fn init_world(&mut self) {
+        // World::new(): Worldのコンストラクタ。新しくWorldを作る
+        self.ecs = World::new();
+
+        // コンポーネントの登録。WorldというECSに登録
+        // これで、各々のコンポーネントの保存システムを内部に作ってくれる
+        self.ecs.register::<Position>();
+        self.ecs.register::<Renderable>();
+        self.ecs.register::<Player>();
+        self.ecs.register::<Viewshed>();
+        self.ecs.register::<Monster>();
+        self.ecs.register::<Name>();
+        self.ecs.register::<BlocksTile>();
+        self.ecs.register::<CombatStats>();
+        self.ecs.register::<WantsToMelee>();
+        self.ecs.register::<SufferDamage>();
+        self.ecs.register::<Item>();
+        self.ecs.register::<InBackpack>();
+        self.ecs.register::<WantsToPickupItem>();
+        self.ecs.register::<WantsToDropItem>();
+        self.ecs.register::<Consumable>();
+        self.ecs.register::<ProvidesHealing>();
+        self.ecs.register::<Ranged>();
+        self.ecs.register::<InflictsDamage>();
+        self.ecs.register::<AreaOfEffect>();
+        self.ecs.register::<Confusion>();
+        self.ecs.register::<WantsToUseItem>();
+        self.ecs.register::<Initiative>();
+        self.ecs.register::<MyTurn>();
+        self.ecs.register::<Brain>();
+        self.ecs.register::<Experience>();
+        self.ecs.register::<ExperienceReward>();
+        self.ecs.register::<Equippable>();
+        self.ecs.register::<Equipped>();
+        self.ecs.register::<MeleeWeapon>();
+        self.ecs.register::<Attributes>();
+        self.ecs.register::<Skills>();
+        self.ecs.register::<DefenseBonus>();
+        self.ecs.register::<WantsToRemoveItem>();
+        self.ecs.register::<SimpleMarker<SerializeMe>>();
+        self.ecs.register::<SerializationHelper>();
+
+        self.ecs.insert(SimpleMarkerAllocator::<SerializeMe>::new());
+
+        self.ecs.insert(GameStats::default());
+        self.ecs.insert(rltk::RandomNumberGenerator::new());
+        self.ecs.insert(GameLog::new());
+
+        // マップを「リソース」にする
+        // つまりECS全体の共有データにする
+        // ecs.get, ecs.fetch, get_mut などでアクセスできる
+        // 中身はgenerate_world_mapかload_gameで入れる
+        self.ecs.insert(Map::new(1));
+        self.ecs.insert(Point::new(0, 0));
+        self.ecs.insert(DijkstraMaps::default());
+        self.ecs.insert(gui::TargetingCursor{ pos: Point::new(0, 0), last_mouse: None });
+        self.ecs.insert(RunState::MainMenu{ menu_selection: gui::MainMenuSelection::NewGame });
+    }
+
+    /// Worldを作り直して新しいゲームを始める
+    fn new_game(&mut self, seed: u64) {
+        self.playback = None;
+        self.auto_move = None;
+        self.quit_without_saving = false;
+        self.init_world();
+
+        // 乱数はすべてこのシード付きRNGから引く。リソースにしておけばどのシステムからも使える
+        // シードを控えておけば同じダンジョンを再現できる
+        self.ecs.insert(rltk::RandomNumberGenerator::seeded(seed));
+
+        let mut gamelog = GameLog::new();
+        gamelog.log("Welcome to Rusty Roguelike");
+        LogBuilder::new()
+            .color(rltk::RGB::named(rltk::GREY))
+            .append(format!("Seed: {}", seed))
+            .log(&mut gamelog);
+        self.ecs.insert(gamelog);
+
+        // 空っぽのエンティティつくって、コンポーネントをくっつける
+        let player_entity = spawner::player(&mut self.ecs, 0, 0);
+        self.ecs.insert(player_entity);
+
+        self.generate_world_map(1);
+        self.ecs.insert(RunState::PreRun);
+    }
+
+    /// Worldを作り直してセーブファイルから続きを読み込み、再開する状態を返す
+    fn continue_game(&mut self) -> RunState {
+        // 途中から読み込んだゲームは最初からのリプレイにならないので記録しない
+        self.recorder = None;
+        self.playback = None;
+        self.auto_move = None;
+        self.quit_without_saving = false;
+        // ファイルとヘッダを確かめてからWorldを作り直す。読めないファイルで今のWorldを壊さない
+        let loaded = saveload_system::read_save(&self.save_path).and_then(|data| {
+            self.init_world();
+            saveload_system::load_game(&mut self.ecs, &data)
+        });
+        match loaded {
+            Ok(()) => {
+                // パーマデス: 読み込んだセーブは消す。load_gameが再開する状態を決める
+                saveload_system::delete_save(&self.save_path);
+                *self.ecs.fetch::<RunState>()
+            }
+            Err(e) => {
+                // 読めなかったときは、ファイルをどかして (Continueが選べなくなる) 新しいゲームを始め、ログで知らせる
+                let bad_path = saveload_system::set_aside_bad_save(&self.save_path);
+                let seed = rltk::RandomNumberGenerator::new().next_u64();
+                self.new_game(seed);
+                self.start_recording(seed);
+                self.ecs.write_resource::<GameLog>().log(format!("{}. The save file was moved to {}", e, bad_path));
+                RunState::PreRun
+            }
+        }
+    }
+
+    fn goto_next_level(&mut self) {
+        let to_delete = self.entities_to_remove_on_level_change();
+        for target in to_delete {
+            self.ecs.delete_entity(target).expect("Unable to delete entity");
+        }
+
+        let current_depth = self.ecs.fetch::<Map>().depth;
+        self.generate_world_map(current_depth + 1);
+
+        LogBuilder::new()
+            .append("You descend to depth ")
+            .color(rltk::RGB::named(rltk::CYAN))
+            .append(current_depth + 1)
+            .color(rltk::RGB::named(rltk::WHITE))
+            .append(".")
+            .log(&mut self.ecs.write_resource::<GameLog>());
+    }
 }
 
 fn main() -> rltk::BError {
     use rltk::RltkBuilder;
 
+    let args = cli::parse_args()?;
+    let seed = args.seed.unwrap_or_else(|| rltk::RandomNumberGenerator::new().next_u64());
+
+    // モンスターとアイテムの定義。おかしければ、ゲームを始める前にどこが悪いかを出して終わる
+    raws::load_raws()?;
+
+    // ウィンドウを作らずに、スクリプトかリプレイでゲームだけを動かす
+    if args.headless {
+        match (&args.script, &args.replay) {
+            (Some(script), _) => headless::run_script(script, seed, args.builder)?,
+            (None, Some(path)) => headless::run_replay(path)?,
+            (None, None) => unreachable!("parse_args requires --script or --replay with --headless")
+        }
+        return Ok(());
+    }
+    // リプレイは起動する前に読んでおき、壊れていればウィンドウを開かずに終わる
+    let replay = match &args.replay {
+        Some(path) => Some(replay::Replay::load(path)?),
+        None => None
+    };
+
     // simple80x50: 横80文字縦50文字のターミナルを作る
     let context = RltkBuilder::simple80x50()
         .with_title("Roguelike Tutorial")
@@ -140,83 +729,33 @@ fn main() -> rltk::BError {
 
     // gsという変数にState構造体のコピーをセットする。参照じゃなくて
     let mut gs = State{
-        // World::new(): Worldのコンストラクタ。新しくWorldを作る
         ecs: World::new(),
         // runstate: RunState::Running
+        builder_name: args.builder,
+        seed: args.seed,
+        recorder: None,
+        playback: None,
+        auto_move: None,
+        quit_without_saving: false,
+        save_path: saveload_system::SAVE_PATH.to_string(),
     };
 
-    // コンポーネントの登録。WorldというECSに登録
-    // これで、各々のコンポーネントの保存システムを内部に作ってくれる
-    gs.ecs.register::<Position>();
-    gs.ecs.register::<Renderable>();
-    gs.ecs.register::<Player>();
-    gs.ecs.register::<Viewshed>();
-    gs.ecs.register::<Monster>();
-    gs.ecs.register::<Name>();
-    gs.ecs.register::<BlocksTile>();
-    gs.ecs.register::<CombatStats>();
-    gs.ecs.register::<WantsToMelee>();
-    gs.ecs.register::<SufferDamage>();
-
-    let map: Map = Map::new_map_rooms_and_corridors();
-    let (player_x, player_y) = map.rooms[0].center();
-
-    // 空っぽのエンティティつくって、コンポーネントをくっつける
-    // Player作成
-    let player_entity = gs.ecs
-        .create_entity()
-        .with(Position {x: player_x, y: player_y})
-        .with(Renderable {
-            glyph: rltk::to_cp437('@'),
-            fg: RGB::named(rltk::YELLOW),
-            bg: RGB::named(rltk::BLACK),
-        })
-        .with(Player{})
-        .with(Viewshed{ visible_tiles : Vec::new(), range : 8, dirty: true })
-        .with(Name{ name: "Player".to_string() })
-        .with(CombatStats{ max_hp: 30, hp: 30, defense: 2, power: 5})
-        .build();
-
-    // Monster作成
-    let mut rng = rltk::RandomNumberGenerator::new();
-    for (i, room) in map.rooms.iter().skip(1).enumerate() {
-        let (x, y) = room.center();
-
-        let glyph : rltk::FontCharType;
-        let name : String;
-        let roll = rng.roll_dice(1, 2);
-        match roll {
-            1 => {glyph = rltk::to_cp437('g'); name = "Goblin".to_string();}
-            _ => {glyph = rltk::to_cp437('o'); name = "Orc".to_string();}
-        }
-
-        gs.ecs.create_entity()
-            .with(Position{x, y})
-            .with(Renderable{
-                glyph: glyph,
-                fg: RGB::named(rltk::RED),
-                bg: RGB::named(rltk::BLACK)
-            })
-            .with(Viewshed{
-                visible_tiles: Vec::new(),
-                range: 8,
-                dirty: true
-            })
-            .with(Monster{})
-            .with(Name{ name: format!("{} #{}", &name, i)})
-            .with(BlocksTile{})
-            .with(CombatStats{ max_hp: 16, hp: 16, defense: 1, power: 4})
-            .build();
-    }
-
-    // マップを作り、「リソース」にする
-    // つまりECS全体の共有データにする
-    // ecs.get, ecs.fetch, get_mut などでアクセスできる
-    gs.ecs.insert(map);
-    
-    gs.ecs.insert(Point::new(player_x, player_y));
-    gs.ecs.insert(player_entity);
-    gs.ecs.insert(RunState::PreRun);
+    // ウィンドウを閉じたときにセーブできるよう、CloseRequestedをイベントとして受け取る
+    rltk::INPUT.lock().activate_event_queue();
+
+    // ゲームはメインメニューで選んでから作る。セーブがあればContinueを選んだ状態で始める
+    // リプレイならメニューを飛ばして、記録と同じシードとマップビルダーで始めて再生する
+    if let Some(replay) = replay {
+        gs.builder_name = replay.builder_name.clone();
+        gs.new_game(replay.seed);
+        // 再生中のファイルがREPLAY_PATHのこともあるので、再生するゲームは記録しない
+        gs.playback = Some(replay);
+    } else {
+        gs.init_world();
+        if saveload_system::does_save_exist(&gs.save_path) {
+            *gs.ecs.write_resource::<RunState>() = RunState::MainMenu{ menu_selection: gui::MainMenuSelection::Continue };
+        }
+    }
 
     // メインループ: UIの表示やゲームを走らせ続けるなどの複雑なところを受け持つ
     // こいつがtick関数を毎度呼ぶことになる
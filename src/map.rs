@@ -1,18 +1,25 @@
-use rltk::{RGB, Rltk, RandomNumberGenerator, Algorithm2D, BaseMap, Point};
-use crate::Viewshed;
-
+use rltk::{RGB, Rltk, Algorithm2D, BaseMap, Point};
 use super::{Rect};
-use std::cmp::{max, min};
 use specs::prelude::*;
+use serde::{Serialize, Deserialize};
+
+pub const MAPWIDTH : usize = 80;
+// 下の7行はログなどのパネルに使う
+pub const MAPHEIGHT : usize = 43;
+pub const MAPCOUNT : usize = MAPHEIGHT * MAPWIDTH;
+
+/// ななめに1歩動くコスト。上下左右は1.0
+pub const DIAGONAL_COST : f32 = 1.45;
 
 // PartialEq: == によって型がマッチしているか調べられるようになる。tile_type == TileType::Wallのような感じで
 // Copy: tile1 = tile2 としたときに、同じものを参照するのではなく？コピーが作られる動きになる。
 // Clone: .clone()メソッドが追加される。
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum TileType {
-    Wall, Floor
+    Wall, Floor, DownStairs
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Map {
   pub tiles : Vec<TileType>,
   pub rooms : Vec<Rect>,
@@ -21,7 +28,11 @@ pub struct Map {
   pub revealed_tiles : Vec<bool>,
   pub visible_tiles : Vec<bool>,
   pub blocked: Vec<bool>,
-  pub tile_content : Vec<Vec<Entity>>
+  // Entityはそのままではセーブできない。ロード後にMapIndexingSystemが作り直す
+  #[serde(skip_serializing, skip_deserializing)]
+  pub tile_content : Vec<Vec<Entity>>,
+  /// 何階層目か。1から始まり、階段を降りるたびに増える
+  pub depth : i32
 }
 
 impl Map {
@@ -31,82 +42,19 @@ impl Map {
     (y as usize * self.width as usize) + x as usize
   }
 
-  fn apply_room_to_map(&mut self, room: &Rect) {
-    for y in room.y1 + 1 ..= room.y2 {
-      for x in room.x1 + 1 ..= room.x2 {
-        let idx = self.xy_idx(x, y);
-        self.tiles[idx] = TileType::Floor;
-      }
-    }
-  }
-  
-  fn apply_horizontal_tunnel(&mut self, x1:i32, x2:i32, y:i32) {
-    for x in min(x1, x2) ..= max(x1, x2) {
-      let idx = self.xy_idx(x, y);
-      if idx > 0 && idx < self.width as usize * self.height as usize {
-        self.tiles[idx as usize] = TileType::Floor;
-      }
-    }
-  }
-  
-  fn apply_vertical_tunnel(&mut self, y1:i32, y2:i32, x:i32) {
-    for y in min(y1, y2) ..= max(y1, y2) {
-      let idx = self.xy_idx(x, y);
-      if idx > 0 && idx < self.width as usize * self.height as usize {
-        self.tiles[idx as usize] = TileType::Floor;
-      }
-    }
-  }
-
-  pub fn new_map_rooms_and_corridors() -> Map {
-    let mut map = Map {
-      tiles: vec![TileType::Wall; 80*50],
+  /// 全面が壁の空マップを作る。実際の地形はmap_buildersの各ビルダーが掘る
+  pub fn new(new_depth : i32) -> Map {
+    Map {
+      tiles: vec![TileType::Wall; MAPCOUNT],
       rooms: Vec::new(),
-      width: 80,
-      height: 50,
-      revealed_tiles: vec![false; 80*50],
-      visible_tiles: vec![false; 80*50],
-      blocked: vec![false; 80*50],
-      tile_content: vec![Vec::new(); 80*50]
-    };
-    
-    // const: Can never change
-    const MAX_ROOMS : i32 = 30;
-    const MIN_SIZE : i32 = 6;
-    const MAX_SIZE : i32 = 10;
-  
-    let mut rng = RandomNumberGenerator::new();
-    
-    for _ in 0..MAX_ROOMS {
-      let w = rng.range(MIN_SIZE, MAX_SIZE);
-      let h = rng.range(MIN_SIZE, MAX_SIZE);
-      let x = rng.roll_dice(1, 80 - w - 1) - 1;
-      let y = rng.roll_dice(1, 50 - h - 1) - 1;
-      let new_room = Rect::new(x, y, w, h);
-      let mut ok = true;
-      for other_room in map.rooms.iter() {
-        if new_room.intersect(other_room) { ok = false }
-      }
-      if ok {
-        map.apply_room_to_map(&new_room);
-  
-        if !map.rooms.is_empty() {
-          let (new_x, new_y) = new_room.center();
-          let (prev_x, prev_y) = map.rooms[map.rooms.len()-1].center();
-          if rng.range(0, 2) == 1 {
-            map.apply_horizontal_tunnel(prev_x, new_x, prev_y);
-            map.apply_vertical_tunnel(prev_y, new_y, new_x);
-          } else {
-            map.apply_vertical_tunnel(prev_y, new_y, prev_x);
-            map.apply_horizontal_tunnel(prev_x, new_x, new_y);
-          }
-        }
-  
-        map.rooms.push(new_room);
-      }
+      width: MAPWIDTH as i32,
+      height: MAPHEIGHT as i32,
+      revealed_tiles: vec![false; MAPCOUNT],
+      visible_tiles: vec![false; MAPCOUNT],
+      blocked: vec![false; MAPCOUNT],
+      tile_content: vec![Vec::new(); MAPCOUNT],
+      depth: new_depth
     }
-  
-    map
   }
 
   /// そのExitの座標が壁などブロックタイルでなければ、通れるものとしてTrue
@@ -126,6 +74,17 @@ impl Map {
     }
   }
 
+  /// fromからtoまでBresenhamの線を引き、途中に視線をさえぎるタイルがなければtrue。
+  /// 飛び道具が届くかどうかの判定に使う
+  pub fn has_line_of_fire(&self, from: Point, to: Point) -> bool {
+    rltk::line2d(rltk::LineAlg::Bresenham, from, to).iter()
+      .filter(|p| **p != from)
+      .all(|p| {
+        p.x >= 0 && p.x < self.width && p.y >= 0 && p.y < self.height
+          && !self.is_opaque(self.xy_idx(p.x, p.y))
+      })
+  }
+
   pub fn clear_content_index(&mut self) {
     for content in self.tile_content.iter_mut() {
       content.clear();
@@ -190,6 +149,10 @@ pub fn draw_map(ecs: &World, ctx: &mut Rltk) {
               glyph = rltk::to_cp437('#');
               fg = RGB::from_f32(0., 1.0, 0.);
           }
+          TileType::DownStairs => {
+              glyph = rltk::to_cp437('>');
+              fg = RGB::from_f32(0., 1.0, 1.0);
+          }
       }
       if !map.visible_tiles[idx] { fg = fg.to_greyscale() }
       ctx.set(x, y, fg, RGB::from_f32(0., 0., 0.), glyph);
@@ -197,7 +160,7 @@ pub fn draw_map(ecs: &World, ctx: &mut Rltk) {
 
     // Move the coordinates
     x += 1;
-    if x > 79 {
+    if x > map.width - 1 {
         x = 0;
         y += 1;
       }
@@ -213,7 +176,7 @@ impl Algorithm2D for Map {
 impl BaseMap for Map {
   // opaque: 不透明
   fn is_opaque(&self, idx:usize) -> bool {
-    self.tiles[idx as usize] == TileType::Wall
+    self.tiles[idx] == TileType::Wall
   }
 
   /// 与えられた座標の上下左右斜め方向を見て、それぞれ通れるならExitとして追加
@@ -230,10 +193,10 @@ impl BaseMap for Map {
       if self.is_exit_valid(x, y+1) { exits.push((idx+w, 1.0)) };
 
       // ななめ
-      if self.is_exit_valid(x-1, y-1) { exits.push(((idx-w)-1, 1.45)) };
-      if self.is_exit_valid(x+1, y-1) { exits.push(((idx-w)+1, 1.45)) };
-      if self.is_exit_valid(x+1, y+1) { exits.push(((idx+w)-1, 1.45)) };
-      if self.is_exit_valid(x+1, y+1) { exits.push(((idx+w)+1, 1.45)) };
+      if self.is_exit_valid(x-1, y-1) { exits.push(((idx-w)-1, DIAGONAL_COST)) };
+      if self.is_exit_valid(x+1, y-1) { exits.push(((idx-w)+1, DIAGONAL_COST)) };
+      if self.is_exit_valid(x-1, y+1) { exits.push(((idx+w)-1, DIAGONAL_COST)) };
+      if self.is_exit_valid(x+1, y+1) { exits.push(((idx+w)+1, DIAGONAL_COST)) };
 
       exits
   }
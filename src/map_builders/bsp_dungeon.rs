@@ -0,0 +1,135 @@
+use specs::prelude::*;
+use rltk::RandomNumberGenerator;
+use super::{MapBuilder, Map, Rect, TileType, Position, spawner, apply_room_to_map, connect_rooms, ensure_a_room};
+
+/// BSP (Binary Space Partition): マップを再帰的に四分割していき、
+/// できた区画の中に部屋を置く。部屋同士が重ならず、マップ全体に均等に散らばる
+pub struct BspDungeonBuilder {
+  map : Map,
+  starting_position : Position,
+  rects : Vec<Rect>
+}
+
+impl MapBuilder for BspDungeonBuilder {
+  fn build_map(&mut self, rng : &mut RandomNumberGenerator) {
+    const MAX_ROOMS : usize = 240;
+
+    self.rects.clear();
+    // マップ端に余白を残した最初の区画
+    self.rects.push(Rect::new(2, 2, self.map.width - 5, self.map.height - 5));
+    let first_room = self.rects[0];
+    self.add_subrects(first_room);
+
+    let mut n_rooms = 0;
+    while n_rooms < MAX_ROOMS {
+      let rect = self.get_random_rect(rng);
+      let candidate = self.get_random_sub_rect(rect, rng);
+
+      if self.is_possible(candidate) {
+        apply_room_to_map(&mut self.map, &candidate);
+        self.map.rooms.push(candidate);
+        self.add_subrects(rect);
+      }
+
+      n_rooms += 1;
+    }
+
+    // 左から順に並べて、隣り合う部屋をつなぐ
+    self.map.rooms.sort_by_key(|room| room.x1);
+    for i in 0..self.map.rooms.len().saturating_sub(1) {
+      let room = self.map.rooms[i];
+      let next_room = self.map.rooms[i + 1];
+      connect_rooms(&mut self.map, rng, &room, &next_room);
+    }
+
+    // 最後の部屋の中央に下り階段を置く。部屋がなければ1つ掘るので、最初と最後の部屋は必ずある
+    ensure_a_room(&mut self.map);
+    let (stairs_x, stairs_y) = self.map.rooms[self.map.rooms.len() - 1].center();
+    let stairs_idx = self.map.xy_idx(stairs_x, stairs_y);
+    self.map.tiles[stairs_idx] = TileType::DownStairs;
+
+    let (x, y) = self.map.rooms[0].center();
+    self.starting_position = Position{ x, y };
+  }
+
+  fn spawn_entities(&mut self, ecs : &mut World) {
+    let start_idx = self.map.xy_idx(self.starting_position.x, self.starting_position.y);
+    let areas : Vec<Vec<usize>> = self.map.rooms.iter().map(|room| spawner::room_tiles(&self.map, room)).collect();
+    // スタートの部屋にはモンスターを置かない
+    let safe_tiles = areas.first().map(|tiles| tiles.iter().copied().collect()).unwrap_or_default();
+    spawner::spawn_areas(ecs, &self.map, &areas, start_idx, &safe_tiles);
+  }
+
+  fn get_map(&self) -> Map {
+    self.map.clone()
+  }
+
+  fn get_starting_position(&self) -> Position {
+    self.starting_position
+  }
+}
+
+impl BspDungeonBuilder {
+  pub fn new(new_depth : i32) -> BspDungeonBuilder {
+    BspDungeonBuilder {
+      map : Map::new(new_depth),
+      starting_position : Position{ x: 0, y: 0 },
+      rects : Vec::new()
+    }
+  }
+
+  /// 区画を縦横半分ずつの4つに分けて候補に加える
+  fn add_subrects(&mut self, rect : Rect) {
+    let width = i32::abs(rect.x1 - rect.x2);
+    let height = i32::abs(rect.y1 - rect.y2);
+    let half_width = i32::max(width / 2, 1);
+    let half_height = i32::max(height / 2, 1);
+
+    self.rects.push(Rect::new(rect.x1, rect.y1, half_width, half_height));
+    self.rects.push(Rect::new(rect.x1, rect.y1 + half_height, half_width, half_height));
+    self.rects.push(Rect::new(rect.x1 + half_width, rect.y1, half_width, half_height));
+    self.rects.push(Rect::new(rect.x1 + half_width, rect.y1 + half_height, half_width, half_height));
+  }
+
+  fn get_random_rect(&mut self, rng : &mut RandomNumberGenerator) -> Rect {
+    if self.rects.len() == 1 { return self.rects[0]; }
+    let idx = (rng.roll_dice(1, self.rects.len() as i32) - 1) as usize;
+    self.rects[idx]
+  }
+
+  /// 区画の中に収まるランダムな大きさの部屋を作る
+  fn get_random_sub_rect(&self, rect : Rect, rng : &mut RandomNumberGenerator) -> Rect {
+    let mut result = rect;
+    let rect_width = i32::abs(rect.x1 - rect.x2);
+    let rect_height = i32::abs(rect.y1 - rect.y2);
+
+    let w = i32::max(3, rng.roll_dice(1, i32::min(rect_width, 10)) - 1) + 1;
+    let h = i32::max(3, rng.roll_dice(1, i32::min(rect_height, 10)) - 1) + 1;
+
+    result.x1 += rng.roll_dice(1, 6) - 1;
+    result.y1 += rng.roll_dice(1, 6) - 1;
+    result.x2 = result.x1 + w;
+    result.y2 = result.y1 + h;
+
+    result
+  }
+
+  /// 部屋の周囲1マスを含めて、マップ内に収まり既存の床と重ならないか
+  fn is_possible(&self, rect : Rect) -> bool {
+    let mut expanded = rect;
+    expanded.x1 -= 2;
+    expanded.x2 += 2;
+    expanded.y1 -= 2;
+    expanded.y2 += 2;
+
+    for y in expanded.y1 ..= expanded.y2 {
+      for x in expanded.x1 ..= expanded.x2 {
+        if x > self.map.width - 2 || y > self.map.height - 2 || x < 1 || y < 1 { return false; }
+        let idx = self.map.xy_idx(x, y);
+        if self.map.tiles[idx] != TileType::Wall { return false; }
+      }
+    }
+
+    true
+  }
+}
@@ -0,0 +1,99 @@
+use specs::prelude::*;
+use rltk::RandomNumberGenerator;
+use std::collections::BTreeMap;
+use super::{MapBuilder, Map, TileType, Position, spawner, wall_off_edges,
+  remove_unreachable_areas_returning_most_distant, generate_voronoi_spawn_regions};
+
+/// セルラーオートマトンで自然な洞窟を作る。
+/// ランダムにばらまいた壁を、周囲の壁の数に応じて増やしたり削ったりを繰り返す
+pub struct CellularAutomataBuilder {
+  map : Map,
+  starting_position : Position,
+  noise_areas : BTreeMap<i32, Vec<usize>>
+}
+
+impl MapBuilder for CellularAutomataBuilder {
+  fn build_map(&mut self, rng : &mut RandomNumberGenerator) {
+    const ITERATIONS : i32 = 15;
+
+    // 55%を床、45%を壁にする
+    for y in 1 .. self.map.height - 1 {
+      for x in 1 .. self.map.width - 1 {
+        let roll = rng.roll_dice(1, 100);
+        let idx = self.map.xy_idx(x, y);
+        self.map.tiles[idx] = if roll > 55 { TileType::Wall } else { TileType::Floor };
+      }
+    }
+
+    for _ in 0..ITERATIONS {
+      let mut newtiles = self.map.tiles.clone();
+      let w = self.map.width as usize;
+
+      for y in 1 .. self.map.height - 1 {
+        for x in 1 .. self.map.width - 1 {
+          let idx = self.map.xy_idx(x, y);
+          let neighbors = [
+            idx - 1, idx + 1, idx - w, idx + w,
+            idx - w - 1, idx - w + 1, idx + w - 1, idx + w + 1
+          ].iter().filter(|i| self.map.tiles[**i] == TileType::Wall).count();
+
+          // 周りが壁だらけ、または周りに壁がまったくなければ壁にする
+          newtiles[idx] = if neighbors > 4 || neighbors == 0 { TileType::Wall } else { TileType::Floor };
+        }
+      }
+
+      self.map.tiles = newtiles;
+    }
+
+    // マップ中央から一番近い床をスタート地点にする
+    let (center_x, center_y) = (self.map.width / 2, self.map.height / 2);
+    let w = self.map.width;
+    let start_idx = self.map.tiles.iter().enumerate()
+      .filter(|(_, tile)| **tile == TileType::Floor)
+      .min_by_key(|(i, _)| {
+        let (x, y) = (*i as i32 % w, *i as i32 / w);
+        (x - center_x) * (x - center_x) + (y - center_y) * (y - center_y)
+      })
+      .map(|(i, _)| i)
+      // 床がまったく残らなかったときは、中央を掘ってスタート地点にする
+      .unwrap_or_else(|| {
+        let idx = self.map.xy_idx(center_x, center_y);
+        self.map.tiles[idx] = TileType::Floor;
+        idx
+      });
+    self.starting_position = Position{ x: start_idx as i32 % w, y: start_idx as i32 / w };
+
+    wall_off_edges(&mut self.map);
+    // スタート地点から一番遠い所に下り階段を置く
+    let exit_tile = remove_unreachable_areas_returning_most_distant(&mut self.map, start_idx);
+    self.map.tiles[exit_tile] = TileType::DownStairs;
+
+    self.noise_areas = generate_voronoi_spawn_regions(&self.map, rng);
+  }
+
+  fn spawn_entities(&mut self, ecs : &mut World) {
+    let start_idx = self.map.xy_idx(self.starting_position.x, self.starting_position.y);
+    let areas : Vec<Vec<usize>> = self.noise_areas.values().cloned().collect();
+    // 部屋がないので、スタート地点の周りにはモンスターを置かない
+    let safe_tiles = spawner::tiles_within(&self.map, start_idx, spawner::START_SAFE_RADIUS);
+    spawner::spawn_areas(ecs, &self.map, &areas, start_idx, &safe_tiles);
+  }
+
+  fn get_map(&self) -> Map {
+    self.map.clone()
+  }
+
+  fn get_starting_position(&self) -> Position {
+    self.starting_position
+  }
+}
+
+impl CellularAutomataBuilder {
+  pub fn new(new_depth : i32) -> CellularAutomataBuilder {
+    CellularAutomataBuilder {
+      map : Map::new(new_depth),
+      starting_position : Position{ x: 0, y: 0 },
+      noise_areas : BTreeMap::new()
+    }
+  }
+}
@@ -0,0 +1,140 @@
+use rltk::{RandomNumberGenerator, FastNoise, NoiseType, CellularDistanceFunction, DijkstraMap};
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use super::{Map, Rect, TileType};
+
+pub fn apply_room_to_map(map: &mut Map, room: &Rect) {
+  for y in room.y1 + 1 ..= room.y2 {
+    for x in room.x1 + 1 ..= room.x2 {
+      let idx = map.xy_idx(x, y);
+      map.tiles[idx] = TileType::Floor;
+    }
+  }
+}
+
+pub fn apply_horizontal_tunnel(map: &mut Map, x1:i32, x2:i32, y:i32) {
+  for x in min(x1, x2) ..= max(x1, x2) {
+    let idx = map.xy_idx(x, y);
+    if idx > 0 && idx < map.width as usize * map.height as usize {
+      map.tiles[idx] = TileType::Floor;
+    }
+  }
+}
+
+pub fn apply_vertical_tunnel(map: &mut Map, y1:i32, y2:i32, x:i32) {
+  for y in min(y1, y2) ..= max(y1, y2) {
+    let idx = map.xy_idx(x, y);
+    if idx > 0 && idx < map.width as usize * map.height as usize {
+      map.tiles[idx] = TileType::Floor;
+    }
+  }
+}
+
+/// 部屋がひとつもできなかったとき (極端なシード) のために、マップ中央に部屋をひとつ掘る。
+/// 部屋のあるビルダーは、スタートと階段の部屋を選ぶ前にこれを呼ぶ
+pub fn ensure_a_room(map: &mut Map) {
+  if map.rooms.is_empty() {
+    let room = Rect::new(map.width / 2 - 3, map.height / 2 - 3, 6, 6);
+    apply_room_to_map(map, &room);
+    map.rooms.push(room);
+  }
+}
+
+/// 部屋の中心同士をL字の通路でつなぐ。縦横どちらを先に掘るかはランダム
+pub fn connect_rooms(map: &mut Map, rng: &mut RandomNumberGenerator, from: &Rect, to: &Rect) {
+  let (new_x, new_y) = to.center();
+  let (prev_x, prev_y) = from.center();
+  if rng.range(0, 2) == 1 {
+    apply_horizontal_tunnel(map, prev_x, new_x, prev_y);
+    apply_vertical_tunnel(map, prev_y, new_y, new_x);
+  } else {
+    apply_vertical_tunnel(map, prev_y, new_y, prev_x);
+    apply_horizontal_tunnel(map, prev_x, new_x, new_y);
+  }
+}
+
+/// マップの外周をすべて壁にする。洞窟系のビルダーは端まで掘ってしまうので最後にこれで閉じる
+pub fn wall_off_edges(map: &mut Map) {
+  for x in 0..map.width {
+    let top = map.xy_idx(x, 0);
+    let bottom = map.xy_idx(x, map.height - 1);
+    map.tiles[top] = TileType::Wall;
+    map.tiles[bottom] = TileType::Wall;
+  }
+  for y in 0..map.height {
+    let left = map.xy_idx(0, y);
+    let right = map.xy_idx(map.width - 1, y);
+    map.tiles[left] = TileType::Wall;
+    map.tiles[right] = TileType::Wall;
+  }
+}
+
+/// start_idxから歩いて行けない床を壁で埋める。
+/// 戻り値はstart_idxから最も遠い床のインデックス
+pub fn remove_unreachable_areas_returning_most_distant(map: &mut Map, start_idx: usize) -> usize {
+  // is_exit_validはblockedを見るので、先に壁の情報を入れておく
+  map.populate_blocked();
+  let map_starts : Vec<usize> = vec![start_idx];
+  let dijkstra_map = DijkstraMap::new(map.width, map.height, &map_starts, map, 200.0);
+
+  // 行ける床がスタート地点しかなければ、スタート地点を返す
+  let mut exit_tile = (start_idx, 0.0f32);
+  for (i, tile) in map.tiles.iter_mut().enumerate() {
+    if *tile == TileType::Floor {
+      let distance_to_start = dijkstra_map.map[i];
+      // 到達できないタイルはf32::MAXのまま
+      if distance_to_start == f32::MAX {
+        *tile = TileType::Wall;
+      } else if distance_to_start > exit_tile.1 {
+        exit_tile = (i, distance_to_start);
+      }
+    }
+  }
+  map.populate_blocked();
+
+  exit_tile.0
+}
+
+/// 部屋のない洞窟系マップで、モンスターを配置する区画を作る。
+/// セルラーノイズの値ごとに床タイルをまとめる
+pub fn generate_voronoi_spawn_regions(map: &Map, rng: &mut RandomNumberGenerator) -> BTreeMap<i32, Vec<usize>> {
+  let mut noise_areas : BTreeMap<i32, Vec<usize>> = BTreeMap::new();
+  let mut noise = FastNoise::seeded(rng.roll_dice(1, 65536) as u64);
+  noise.set_noise_type(NoiseType::Cellular);
+  noise.set_frequency(0.08);
+  noise.set_cellular_distance_function(CellularDistanceFunction::Manhattan);
+
+  for y in 1 .. map.height - 1 {
+    for x in 1 .. map.width - 1 {
+      let idx = map.xy_idx(x, y);
+      if map.tiles[idx] == TileType::Floor {
+        let cell_value = (noise.get_noise(x as f32, y as f32) * 10240.0) as i32;
+        noise_areas.entry(cell_value).or_default().push(idx);
+      }
+    }
+  }
+
+  noise_areas
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_only_floor_is_the_most_distant_tile() {
+    let mut map = Map::new(1);
+    let start_idx = map.xy_idx(10, 10);
+    map.tiles[start_idx] = TileType::Floor;
+    assert_eq!(remove_unreachable_areas_returning_most_distant(&mut map, start_idx), start_idx);
+  }
+
+  #[test]
+  fn a_map_without_rooms_gets_one() {
+    let mut map = Map::new(1);
+    ensure_a_room(&mut map);
+    assert_eq!(map.rooms.len(), 1);
+    let (x, y) = map.rooms[0].center();
+    assert!(map.tiles[map.xy_idx(x, y)] == TileType::Floor);
+  }
+}
@@ -0,0 +1,85 @@
+use specs::prelude::*;
+use rltk::RandomNumberGenerator;
+use std::collections::BTreeMap;
+use super::{MapBuilder, Map, TileType, Position, spawner, wall_off_edges,
+  remove_unreachable_areas_returning_most_distant, generate_voronoi_spawn_regions};
+
+/// 酔っ払いの千鳥足 (Drunkard's Walk)。
+/// 千鳥足の掘り手がランダムに歩き回りながら床を掘り、
+/// 床の割合が一定を超えるまで掘り手を送り込み続ける
+pub struct DrunkardsWalkBuilder {
+  map : Map,
+  starting_position : Position,
+  noise_areas : BTreeMap<i32, Vec<usize>>
+}
+
+impl MapBuilder for DrunkardsWalkBuilder {
+  fn build_map(&mut self, rng : &mut RandomNumberGenerator) {
+    // 掘り手1人が歩ける歩数
+    const DRUNKEN_LIFETIME : i32 = 400;
+    // マップのうち床にしたい割合
+    const FLOOR_PERCENT : f32 = 0.5;
+
+    // 掘り手は全員マップ中央から出発する。中央は必ず床になる
+    self.starting_position = Position{ x: self.map.width / 2, y: self.map.height / 2 };
+    let start_idx = self.map.xy_idx(self.starting_position.x, self.starting_position.y);
+    self.map.tiles[start_idx] = TileType::Floor;
+
+    let total_tiles = self.map.width * self.map.height;
+    let desired_floor_tiles = (FLOOR_PERCENT * total_tiles as f32) as usize;
+    let mut floor_tile_count = self.map.tiles.iter().filter(|t| **t == TileType::Floor).count();
+
+    while floor_tile_count < desired_floor_tiles {
+      let mut drunk_x = self.starting_position.x;
+      let mut drunk_y = self.starting_position.y;
+
+      for _ in 0..DRUNKEN_LIFETIME {
+        let drunk_idx = self.map.xy_idx(drunk_x, drunk_y);
+        self.map.tiles[drunk_idx] = TileType::Floor;
+
+        // 外周には出ないように歩く
+        match rng.roll_dice(1, 4) {
+          1 => { if drunk_x > 2 { drunk_x -= 1; } }
+          2 => { if drunk_x < self.map.width - 2 { drunk_x += 1; } }
+          3 => { if drunk_y > 2 { drunk_y -= 1; } }
+          _ => { if drunk_y < self.map.height - 2 { drunk_y += 1; } }
+        }
+      }
+
+      floor_tile_count = self.map.tiles.iter().filter(|t| **t == TileType::Floor).count();
+    }
+
+    wall_off_edges(&mut self.map);
+    // スタート地点から一番遠い所に下り階段を置く
+    let exit_tile = remove_unreachable_areas_returning_most_distant(&mut self.map, start_idx);
+    self.map.tiles[exit_tile] = TileType::DownStairs;
+
+    self.noise_areas = generate_voronoi_spawn_regions(&self.map, rng);
+  }
+
+  fn spawn_entities(&mut self, ecs : &mut World) {
+    let start_idx = self.map.xy_idx(self.starting_position.x, self.starting_position.y);
+    let areas : Vec<Vec<usize>> = self.noise_areas.values().cloned().collect();
+    // 部屋がないので、スタート地点の周りにはモンスターを置かない
+    let safe_tiles = spawner::tiles_within(&self.map, start_idx, spawner::START_SAFE_RADIUS);
+    spawner::spawn_areas(ecs, &self.map, &areas, start_idx, &safe_tiles);
+  }
+
+  fn get_map(&self) -> Map {
+    self.map.clone()
+  }
+
+  fn get_starting_position(&self) -> Position {
+    self.starting_position
+  }
+}
+
+impl DrunkardsWalkBuilder {
+  pub fn new(new_depth : i32) -> DrunkardsWalkBuilder {
+    DrunkardsWalkBuilder {
+      map : Map::new(new_depth),
+      starting_position : Position{ x: 0, y: 0 },
+      noise_areas : BTreeMap::new()
+    }
+  }
+}
@@ -0,0 +1,41 @@
+use specs::prelude::*;
+use rltk::RandomNumberGenerator;
+use super::{Map, Rect, TileType, Position, spawner};
+mod common;
+use common::*;
+mod simple_map;
+use simple_map::SimpleMapBuilder;
+mod bsp_dungeon;
+use bsp_dungeon::BspDungeonBuilder;
+mod cellular_automata;
+use cellular_automata::CellularAutomataBuilder;
+mod drunkard;
+use drunkard::DrunkardsWalkBuilder;
+
+/// マップ生成アルゴリズムの共通インターフェース。
+/// 乱数はすべて引数のrng (ECSリソースのシード付きRNG) から引くこと。
+/// 新しいレイアウトを試すときはこのトレイトを実装して、下のBUILDER_NAMESとbuilder_by_nameに足す
+pub trait MapBuilder {
+  fn build_map(&mut self, rng : &mut RandomNumberGenerator);
+  fn spawn_entities(&mut self, ecs : &mut World);
+  fn get_map(&self) -> Map;
+  fn get_starting_position(&self) -> Position;
+}
+
+/// コマンドラインなどから指定できるビルダーの名前
+pub const BUILDER_NAMES : [&str; 4] = ["simple", "bsp", "cellular", "drunkard"];
+
+pub fn builder_by_name(name : &str, new_depth : i32) -> Option<Box<dyn MapBuilder>> {
+  match name {
+    "simple" => Some(Box::new(SimpleMapBuilder::new(new_depth))),
+    "bsp" => Some(Box::new(BspDungeonBuilder::new(new_depth))),
+    "cellular" => Some(Box::new(CellularAutomataBuilder::new(new_depth))),
+    "drunkard" => Some(Box::new(DrunkardsWalkBuilder::new(new_depth))),
+    _ => None
+  }
+}
+
+pub fn random_builder(new_depth : i32, rng : &mut RandomNumberGenerator) -> Box<dyn MapBuilder> {
+  let name = rng.random_slice_entry(&BUILDER_NAMES).unwrap();
+  builder_by_name(name, new_depth).unwrap()
+}
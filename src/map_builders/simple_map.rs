@@ -0,0 +1,71 @@
+use specs::prelude::*;
+use rltk::RandomNumberGenerator;
+use super::{MapBuilder, Map, Rect, TileType, Position, spawner, apply_room_to_map, connect_rooms, ensure_a_room};
+
+/// 元々のnew_map_rooms_and_corridors。
+/// 重ならないように部屋をランダムに置き、ひとつ前の部屋とL字の通路でつなぐ
+pub struct SimpleMapBuilder {
+  map : Map,
+  starting_position : Position
+}
+
+impl MapBuilder for SimpleMapBuilder {
+  fn build_map(&mut self, rng : &mut RandomNumberGenerator) {
+    // const: Can never change
+    const MAX_ROOMS : i32 = 30;
+    const MIN_SIZE : i32 = 6;
+    const MAX_SIZE : i32 = 10;
+
+    for _ in 0..MAX_ROOMS {
+      let w = rng.range(MIN_SIZE, MAX_SIZE);
+      let h = rng.range(MIN_SIZE, MAX_SIZE);
+      let x = rng.roll_dice(1, self.map.width - w - 1) - 1;
+      let y = rng.roll_dice(1, self.map.height - h - 1) - 1;
+      let new_room = Rect::new(x, y, w, h);
+      let ok = !self.map.rooms.iter().any(|other_room| new_room.intersect(other_room));
+      if ok {
+        apply_room_to_map(&mut self.map, &new_room);
+
+        if let Some(prev_room) = self.map.rooms.last().copied() {
+          connect_rooms(&mut self.map, rng, &prev_room, &new_room);
+        }
+
+        self.map.rooms.push(new_room);
+      }
+    }
+
+    // 最後の部屋の中央に下り階段を置く。部屋がなければ1つ掘るので、最初と最後の部屋は必ずある
+    ensure_a_room(&mut self.map);
+    let (stairs_x, stairs_y) = self.map.rooms[self.map.rooms.len() - 1].center();
+    let stairs_idx = self.map.xy_idx(stairs_x, stairs_y);
+    self.map.tiles[stairs_idx] = TileType::DownStairs;
+
+    let (x, y) = self.map.rooms[0].center();
+    self.starting_position = Position{ x, y };
+  }
+
+  fn spawn_entities(&mut self, ecs : &mut World) {
+    let start_idx = self.map.xy_idx(self.starting_position.x, self.starting_position.y);
+    let areas : Vec<Vec<usize>> = self.map.rooms.iter().map(|room| spawner::room_tiles(&self.map, room)).collect();
+    // スタートの部屋にはモンスターを置かない
+    let safe_tiles = areas.first().map(|tiles| tiles.iter().copied().collect()).unwrap_or_default();
+    spawner::spawn_areas(ecs, &self.map, &areas, start_idx, &safe_tiles);
+  }
+
+  fn get_map(&self) -> Map {
+    self.map.clone()
+  }
+
+  fn get_starting_position(&self) -> Position {
+    self.starting_position
+  }
+}
+
+impl SimpleMapBuilder {
+  pub fn new(new_depth : i32) -> SimpleMapBuilder {
+    SimpleMapBuilder {
+      map : Map::new(new_depth),
+      starting_position : Position{ x: 0, y: 0 }
+    }
+  }
+}
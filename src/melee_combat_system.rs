@@ -1,42 +1,140 @@
-use rltk::{console};
 use specs::prelude::*;
-use super::{CombatStats, WantsToMelee, Name, SufferDamage};
+use rltk::RandomNumberGenerator;
+use super::{CombatStats, WantsToMelee, Name, SufferDamage, GameLog, LogBuilder, Equipped, EquipmentSlot, MeleeWeapon, DefenseBonus,
+  Attributes, Skills, attr_bonus};
+
+/// 武器を持たず、体にも武器がない者の攻撃 (素手)
+pub const UNARMED : MeleeWeapon = MeleeWeapon{ damage_n_dice: 1, damage_die_type: 4, damage_bonus: 0, hit_bonus: 0 };
+
+/// 何も着ていない者のアーマークラス
+const BASE_ARMOR_CLASS : i32 = 10;
+
+/// ownerが攻撃に使う武器。装備している武器、なければ本人の体 (牙や爪)、それもなければ素手
+pub fn melee_weapon(owner : Entity, equipped : &ReadStorage<Equipped>, weapons : &ReadStorage<MeleeWeapon>) -> MeleeWeapon {
+  (equipped, weapons).join()
+    .find(|(item, _)| item.owner == owner && item.slot == EquipmentSlot::Melee)
+    .map(|(_, weapon)| weapon)
+    .or_else(|| weapons.get(owner))
+    .cloned()
+    .unwrap_or(UNARMED)
+}
+
+/// ownerが装備している防具のボーナスの合計
+pub fn defense_bonus(owner : Entity, equipped : &ReadStorage<Equipped>, bonuses : &ReadStorage<DefenseBonus>) -> i32 {
+  (equipped, bonuses).join()
+    .filter(|(item, _)| item.owner == owner)
+    .map(|(_, bonus)| bonus.defense)
+    .sum()
+}
+
+/// 攻撃を当てるのに要る出目。10 + 防御の技能 + 防具
+pub fn armor_class(owner : Entity, attributes : &Attributes, skills : &Skills,
+  equipped : &ReadStorage<Equipped>, bonuses : &ReadStorage<DefenseBonus>) -> i32 {
+  BASE_ARMOR_CLASS + skills.defense_bonus(attributes) + defense_bonus(owner, equipped, bonuses)
+}
+
+/// 1回の攻撃の結果
+enum Swing {
+  /// 1の目。必ず外れる
+  Fumble,
+  Miss,
+  /// criticalは20の目。必ず当たり、ダイスを2倍振る
+  Hit { damage : i32, critical : bool }
+}
+
+/// 近接攻撃をd20で解決する。
+/// d20 + 近接の技能 + 武器の命中ボーナス がアーマークラス以上なら当たり、
+/// ダメージは 武器のダイス + 武器のボーナス + 腕力のボーナス (当たれば最低1)
+fn swing(rng : &mut RandomNumberGenerator, attributes : &Attributes, skills : &Skills, weapon : &MeleeWeapon, armor_class : i32) -> Swing {
+  let natural_roll = rng.roll_dice(1, 20);
+  let critical = natural_roll == 20;
+  if natural_roll == 1 {
+    return Swing::Fumble;
+  }
+  if !critical && natural_roll + skills.melee_bonus(attributes) + weapon.hit_bonus < armor_class {
+    return Swing::Miss;
+  }
+
+  let n_dice = if critical { weapon.damage_n_dice * 2 } else { weapon.damage_n_dice };
+  let roll = rng.roll_dice(n_dice, weapon.damage_die_type);
+  let damage = i32::max(1, roll + weapon.damage_bonus + attr_bonus(attributes.might));
+  Swing::Hit{ damage, critical }
+}
 
 pub struct MeleeCombatSystem {}
 
 impl<'a> System<'a> for MeleeCombatSystem {
+  #[allow(clippy::type_complexity)]
   type SystemData = (
     Entities<'a>,
     WriteStorage<'a, WantsToMelee>,
     ReadStorage<'a, Name>,
     ReadStorage<'a, CombatStats>,
     WriteStorage<'a, SufferDamage>,
+    WriteExpect<'a, GameLog>,
+    ReadStorage<'a, Equipped>,
+    ReadStorage<'a, MeleeWeapon>,
+    ReadStorage<'a, DefenseBonus>,
+    ReadStorage<'a, Attributes>,
+    ReadStorage<'a, Skills>,
+    WriteExpect<'a, RandomNumberGenerator>
   );
 
   fn run(&mut self, data : Self::SystemData) {
     // inflict: 与える、課す
-    let (entities, mut wants_to_melee, names, combat_stats, mut inflict_damage) = data;
-
-    for (_entity, wants_to_melee, name, stats) in (&entities, &wants_to_melee, &names, &combat_stats).join() {
-      if stats.hp > 0 {
-        let target_stats = combat_stats.get(wants_to_melee.target).unwrap();
-        if target_stats.hp > 0 {
-          let target_name = names.get(wants_to_melee.target).unwrap();
-
-          let damage = i32::max(0, stats.power - target_stats.defense);
-
-          if damage == 0 {
-            console::log(&format!("{} is unable to hurt {}", &name.name, &target_name.name));
-          } else {
-            console::log(&format!("{} hits {}, for {} hp", &name.name, &target_name.name, damage));
-            
-            // storage, victim, amount
-            SufferDamage::new_damage(&mut inflict_damage, wants_to_melee.target, damage);
-          }
+    let (entities, mut wants_to_melee, names, combat_stats, mut inflict_damage, mut log,
+      equipped, weapons, defense_bonuses, attributes, skills, mut rng) = data;
+
+    for (entity, wants_to_melee, name, stats, attacker_attributes, attacker_skills)
+      in (&entities, &wants_to_melee, &names, &combat_stats, &attributes, &skills).join() {
+      if stats.hp <= 0 {
+        continue;
+      }
+      let target = wants_to_melee.target;
+      let (target_stats, target_name, target_attributes, target_skills) =
+        match (combat_stats.get(target), names.get(target), attributes.get(target), skills.get(target)) {
+          (Some(stats), Some(name), Some(attributes), Some(skills)) => (stats, name, attributes, skills),
+          _ => continue
+        };
+      if target_stats.hp <= 0 {
+        continue;
+      }
+
+      let weapon = melee_weapon(entity, &equipped, &weapons);
+      let armor_class = armor_class(target, target_attributes, target_skills, &equipped, &defense_bonuses);
+
+      match swing(&mut rng, attacker_attributes, attacker_skills, &weapon, armor_class) {
+        Swing::Fumble => {
+          LogBuilder::new()
+            .npc_name(&name.name)
+            .append(" fumbles and misses ")
+            .npc_name(&target_name.name)
+            .log(&mut log);
+        }
+        Swing::Miss => {
+          LogBuilder::new()
+            .npc_name(&name.name)
+            .append(" misses ")
+            .npc_name(&target_name.name)
+            .log(&mut log);
+        }
+        Swing::Hit { damage, critical } => {
+          let verb = if critical { " critically hits " } else { " hits " };
+          LogBuilder::new()
+            .npc_name(&name.name)
+            .append(verb)
+            .npc_name(&target_name.name)
+            .append(", for ")
+            .damage(damage)
+            .append(" hp")
+            .log(&mut log);
+
+          // storage, victim, amount, from
+          SufferDamage::new_damage(&mut inflict_damage, target, damage, entity);
         }
       }
     }
 
     wants_to_melee.clear();
   }
-}
\ No newline at end of file
+}
@@ -1,62 +1,232 @@
 use specs::prelude::*;
-use crate::WantsToMelee;
+use crate::{WantsToMelee, Confusion, Name, GameLog, LogBuilder, Initiative, MyTurn, CombatStats, Brain, AiState};
+use crate::initiative_system::{MOVE_COST, ATTACK_COST, WAIT_COST};
+use crate::dijkstra_system::{DijkstraMaps, downhill_step};
 
-use super::{Viewshed, Monster, Name, Map, Position, RunState};
-use rltk::{Point, console};
+use super::{Viewshed, Monster, Map, Position};
+use rltk::{Point, BaseMap, RandomNumberGenerator};
+
+/// 眠っているモンスターがプレイヤーに気づく距離
+const WAKE_DISTANCE : f32 = 4.0;
+
+/// 最後にプレイヤーを見た場所に着いてから、探し回るターン数
+const SEARCH_TURNS : i32 = 8;
 
 pub struct MonsterAI {}
 
+/// 見えているもの、体力から、次の行動方針を決める
+fn next_state(state : AiState, sees_player : bool, distance : f32, stats : &CombatStats, flees : bool) -> AiState {
+  // 傷を負うか、近くでプレイヤーを見たら目を覚ます
+  let woken = stats.hp < stats.max_hp || (sees_player && distance <= WAKE_DISTANCE);
+  // 体力が1/4以下になったら逃げる (逃げないモンスターもいる)
+  let weak = flees && stats.hp * 4 <= stats.max_hp;
+
+  match state {
+    AiState::Idle if !woken => AiState::Idle,
+    // 起きたものの、プレイヤーがどこにいるかはわからない
+    AiState::Idle if !sees_player => AiState::Wandering,
+    _ if sees_player && weak => AiState::Fleeing,
+    _ if sees_player => AiState::Chasing,
+    AiState::Chasing => AiState::Searching{ turns: SEARCH_TURNS },
+    AiState::Fleeing => AiState::Wandering,
+    other => other
+  }
+}
+
+/// 隣のタイルに1歩動く
+fn step_to(map : &mut Map, pos : &mut Position, viewshed : &mut Viewshed, dest_idx : usize) {
+  let idx = map.xy_idx(pos.x, pos.y);
+  map.blocked[idx] = false;
+
+  pos.x = dest_idx as i32 % map.width;
+  pos.y = dest_idx as i32 / map.width;
+
+  map.blocked[dest_idx] = true;
+  viewshed.dirty = true;
+}
+
+/// targetに向かって1歩進む。進めたらtrue
+fn step_towards(map : &mut Map, pos : &mut Position, viewshed : &mut Viewshed, target : Point) -> bool {
+  // A star search: 経路探索アルゴリズム。最短経路探索に優れる
+  let path = rltk::a_star_search(
+    map.xy_idx(pos.x, pos.y) as i32,
+    map.xy_idx(target.x, target.y) as i32,
+    &*map
+  );
+
+  // steps[0]は今いるところ
+  if path.success && path.steps.len() > 1 {
+    step_to(map, pos, viewshed, path.steps[1]);
+    return true;
+  }
+  false
+}
+
+/// 行ける隣のタイルにでたらめに1歩動く。動けたらtrue
+fn step_randomly(map : &mut Map, pos : &mut Position, viewshed : &mut Viewshed, rng : &mut RandomNumberGenerator) -> bool {
+  let exits = map.get_available_exits(map.xy_idx(pos.x, pos.y));
+  if exits.is_empty() {
+    return false;
+  }
+  let (dest_idx, _) = exits[(rng.roll_dice(1, exits.len() as i32) - 1) as usize];
+  step_to(map, pos, viewshed, dest_idx);
+  true
+}
+
+/// Dijkstraマップを1歩下る。動けたらtrue
+fn step_downhill(map : &mut Map, pos : &mut Position, viewshed : &mut Viewshed, values : &[f32]) -> bool {
+  match downhill_step(map, values, map.xy_idx(pos.x, pos.y)) {
+    Some(dest_idx) => {
+      step_to(map, pos, viewshed, dest_idx);
+      true
+    }
+    None => false
+  }
+}
+
 impl<'a> System<'a> for MonsterAI {
   #[allow(clippy::type_complexity)]
   type SystemData = (
     WriteExpect<'a, Map>,
     ReadExpect<'a, Point>,
     ReadExpect<'a, Entity>,
-    ReadExpect<'a, RunState>,
     Entities<'a>,
     WriteStorage<'a, Viewshed>,
     ReadStorage<'a, Monster>,
-    // ReadStorage<'a, Name>,
+    ReadStorage<'a, Name>,
     WriteStorage<'a, Position>,
-    WriteStorage<'a, WantsToMelee>
+    WriteStorage<'a, WantsToMelee>,
+    WriteStorage<'a, Confusion>,
+    WriteExpect<'a, GameLog>,
+    WriteStorage<'a, Initiative>,
+    WriteStorage<'a, MyTurn>,
+    ReadStorage<'a, CombatStats>,
+    WriteStorage<'a, Brain>,
+    WriteExpect<'a, RandomNumberGenerator>,
+    ReadExpect<'a, DijkstraMaps>
   );
 
   fn run(&mut self, data : Self::SystemData) {
-    let (mut map, player_pos, player_entity,  runstate, entities, mut viewshed, monster, mut position, mut wants_to_melee) = data;
-
-    if *runstate != RunState::MonsterTurn {return;}
-    
-    for (entity, mut viewshed, _monster, mut pos) in (&entities, &mut viewshed, &monster, &mut position).join() {
-      if viewshed.visible_tiles.contains(&*player_pos) {
-        let distance = rltk::DistanceAlg::Pythagoras.distance2d(Point::new(pos.x, pos.y), *player_pos);
-        // プレイヤーに隣接したら攻撃。Returnでこれ以上近づかない
-        if distance < 1.5 {
-          wants_to_melee.insert(entity, WantsToMelee { target: *player_entity }).expect("Unable to insert attack");
+    let (mut map, player_pos, player_entity, entities, mut viewshed, monster, names, mut position, mut wants_to_melee,
+      mut confused, mut gamelog, mut initiatives, mut turns, combat_stats, mut brains, mut rng, dijkstra) = data;
+
+    // スケジューラが行動の番 (MyTurn) をくれたモンスターだけが動く
+    let mut acted = Vec::new();
+    for (entity, viewshed, _monster, pos, initiative, _turn, stats, brain) in
+      (&entities, &mut viewshed, &monster, &mut position, &mut initiatives, &turns, &combat_stats, &mut brains).join() {
+      acted.push(entity);
+
+      // 混乱中はこのターン何もできない
+      if let Some(i_am_confused) = confused.get_mut(entity) {
+        i_am_confused.turns -= 1;
+        if i_am_confused.turns < 1 {
+          confused.remove(entity);
+          if let Some(name) = names.get(entity) {
+            LogBuilder::new().npc_name(&name.name).append(" is no longer confused.").log(&mut gamelog);
+          }
+        }
+        initiative.spend(WAIT_COST);
+        continue;
+      }
+
+      let sees_player = viewshed.visible_tiles.contains(&*player_pos);
+      let distance = rltk::DistanceAlg::Pythagoras.distance2d(Point::new(pos.x, pos.y), *player_pos);
+      if sees_player {
+        brain.last_seen_player = Some(*player_pos);
+      }
+
+      let state = next_state(brain.state, sees_player, distance, stats, brain.flees);
+      if state == AiState::Fleeing && brain.state != AiState::Fleeing {
+        if let Some(name) = names.get(entity) {
+          LogBuilder::new().npc_name(&name.name).append(" turns to flee!").log(&mut gamelog);
+        }
+      }
+      brain.state = state;
+
+      let mut cost = WAIT_COST;
+      match brain.state {
+        AiState::Idle => {}
+        AiState::Wandering => {
+          if step_randomly(&mut map, pos, viewshed, &mut rng) {
+            cost = MOVE_COST;
+          }
+        }
+        AiState::Chasing => {
+          // プレイヤーに隣接したら攻撃。隣接してないなら近づく
+          if distance < 1.5 {
+            wants_to_melee.insert(entity, WantsToMelee { target: *player_entity }).expect("Unable to insert attack");
+            cost = ATTACK_COST;
+          } else if step_downhill(&mut map, pos, viewshed, &dijkstra.approach_player) {
+            cost = MOVE_COST;
+          }
+        }
+        AiState::Fleeing => {
+          if step_downhill(&mut map, pos, viewshed, &dijkstra.flee_player) {
+            cost = MOVE_COST;
+          } else if distance < 1.5 {
+            // 追いつめられたら反撃する
+            wants_to_melee.insert(entity, WantsToMelee { target: *player_entity }).expect("Unable to insert attack");
+            cost = ATTACK_COST;
+          }
         }
-        // 隣接してないなら移動
-        else if viewshed.visible_tiles.contains(&*player_pos) {
-          // A star search: 経路探索アルゴリズム。最短経路探索に優れる
-          let path = rltk::a_star_search(
-            map.xy_idx(pos.x, pos.y) as i32,
-            map.xy_idx(player_pos.x, player_pos.y) as i32,
-            &mut *map
-          );
-
-          if path.success && path.steps.len() > 1 {
-            let mut idx = map.xy_idx(pos.x, pos.y);
-            map.blocked[idx] = false;
-
-            // steps[0]は今いるところ
-            // Playerに向かって1歩進む
-            pos.x = path.steps[1] as i32 % map.width;
-            pos.y = path.steps[1] as i32 / map.width;
-            idx = map.xy_idx(pos.x, pos.y);
-
-            map.blocked[idx] = true;
-            viewshed.dirty = true;
+        AiState::Searching { turns } => {
+          // まず最後に見た場所まで行く。着いたか、行けなければそのあたりを探し回る
+          let heading_to = brain.last_seen_player.filter(|target| *target != Point::new(pos.x, pos.y));
+          let moved = match heading_to {
+            Some(target) => step_towards(&mut map, pos, viewshed, target),
+            None => false
+          };
+          if moved {
+            cost = MOVE_COST;
+          } else {
+            brain.last_seen_player = None;
+            if step_randomly(&mut map, pos, viewshed, &mut rng) {
+              cost = MOVE_COST;
+            }
+            brain.state = if turns > 1 { AiState::Searching{ turns: turns - 1 } } else { AiState::Wandering };
           }
         }
       }
+      initiative.spend(cost);
     }
+
+    for entity in acted {
+      turns.remove(entity);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stats(hp : i32) -> CombatStats {
+    CombatStats{ max_hp: 16, hp }
+  }
+
+  #[test]
+  fn sleeping_monsters_wake_up_near_the_player_or_when_hurt() {
+    assert_eq!(next_state(AiState::Idle, true, 8.0, &stats(16), true), AiState::Idle);
+    assert_eq!(next_state(AiState::Idle, false, 2.0, &stats(16), true), AiState::Idle);
+    assert_eq!(next_state(AiState::Idle, true, 3.0, &stats(16), true), AiState::Chasing);
+    assert_eq!(next_state(AiState::Idle, false, 20.0, &stats(10), true), AiState::Wandering);
+    assert_eq!(next_state(AiState::Idle, true, 20.0, &stats(10), true), AiState::Chasing);
+  }
+
+  #[test]
+  fn monsters_chase_search_and_give_up() {
+    assert_eq!(next_state(AiState::Wandering, true, 7.0, &stats(16), true), AiState::Chasing);
+    assert_eq!(next_state(AiState::Chasing, false, 7.0, &stats(16), true), AiState::Searching{ turns: SEARCH_TURNS });
+    assert_eq!(next_state(AiState::Searching{ turns: 3 }, false, 7.0, &stats(16), true), AiState::Searching{ turns: 3 });
+    assert_eq!(next_state(AiState::Searching{ turns: 3 }, true, 7.0, &stats(16), true), AiState::Chasing);
+    assert_eq!(next_state(AiState::Wandering, false, 7.0, &stats(16), true), AiState::Wandering);
+  }
+
+  #[test]
+  fn weak_monsters_flee_unless_they_fight_to_the_death() {
+    assert_eq!(next_state(AiState::Chasing, true, 1.0, &stats(4), true), AiState::Fleeing);
+    assert_eq!(next_state(AiState::Chasing, true, 1.0, &stats(5), true), AiState::Chasing);
+    assert_eq!(next_state(AiState::Chasing, true, 1.0, &stats(4), false), AiState::Chasing);
+    assert_eq!(next_state(AiState::Fleeing, false, 9.0, &stats(4), true), AiState::Wandering);
   }
-}
\ No newline at end of file
+}
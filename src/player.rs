@@ -1,11 +1,17 @@
-use rltk::{VirtualKeyCode, Rltk, Point, console};
+use rltk::{VirtualKeyCode, Rltk, Point};
 use specs::prelude::*;
-use crate::{CombatStats, WantsToMelee};
+use crate::{CombatStats, WantsToMelee, GameLog, Item, WantsToPickupItem, Monster, gui};
+use crate::{InBackpack, Ranged, WantsToUseItem, WantsToDropItem, Command, Initiative, MyTurn};
+use crate::{EquipmentSlot, Equipped, WantsToRemoveItem, Experience, LevelUpChoice, Attributes, Skills};
+use crate::auto_move::{AutoMove, AutoMoveKind, known_stairs};
+use crate::command::is_step;
+use crate::initiative_system::{MOVE_COST, ATTACK_COST, WAIT_COST, USE_ITEM_COST, PICKUP_COST};
+use crate::damage_system::LEVEL_UP_FITNESS_HP;
 
 use super::{Position, Player, TileType, State, Map, Viewshed, RunState};
-use std::cmp::{min, max};
 
-pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) {
+/// 移動する。移動先にモンスターがいれば攻撃する。使った行動の重さを返す
+pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) -> i32 {
   let mut positions = ecs.write_storage::<Position>();
   let mut players = ecs.write_storage::<Player>();
   let mut viewsheds = ecs.write_storage::<Viewshed>();
@@ -15,7 +21,7 @@ pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) {
   let mut wants_to_melee = ecs.write_storage::<WantsToMelee>();
 
   for (entity, _player, pos, viewshed) in (&entities, &mut players, &mut positions, &mut viewsheds).join() {
-    if pos.x + delta_x < 1 || pos.x + delta_x > map.width-1 || pos.y + delta_y < 1 || pos.y + delta_y > map.height-1 { return; }
+    if pos.x + delta_x < 1 || pos.x + delta_x > map.width-1 || pos.y + delta_y < 1 || pos.y + delta_y > map.height-1 { return MOVE_COST; }
     
     let destination_idx = map.xy_idx(pos.x + delta_x, pos.y + delta_y);
     
@@ -23,13 +29,13 @@ pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) {
       let target = combat_stats.get(*potential_target);
       if let Some(_target) = target {
         wants_to_melee.insert(entity, WantsToMelee { target: *potential_target }).expect("Add target failed");
-        return;
+        return ATTACK_COST;
       }
     }
 
     if !map.blocked[destination_idx] {
-        pos.x = min(79, max(0, pos.x + delta_x));
-        pos.y = min(49, max(0, pos.y + delta_y));
+        pos.x = (pos.x + delta_x).clamp(0, map.width - 1);
+        pos.y = (pos.y + delta_y).clamp(0, map.height - 1);
 
         // プレイヤーが動いたら、視野をdirty、つまり要更新にする
         viewshed.dirty = true;
@@ -39,43 +45,383 @@ pub fn try_move_player(delta_x: i32, delta_y: i32, ecs: &mut World) {
     ppos.x = pos.x;
     ppos.y = pos.y;
   }
+  MOVE_COST
 }
 
-pub fn player_input(gs: &mut State, ctx: &mut Rltk) -> RunState {
-  match ctx.key {
-      None => { return RunState::AwaitingInput } // 何も起こらない
-      Some(key) => match key {
-          VirtualKeyCode::Left | 
-          VirtualKeyCode::Numpad4 |
-          VirtualKeyCode::H => try_move_player(-1, 0, &mut gs.ecs),
-
-          VirtualKeyCode::Right | 
-          VirtualKeyCode::Numpad6 |
-          VirtualKeyCode::L => try_move_player(1, 0, &mut gs.ecs),
-
-          VirtualKeyCode::Up | 
-          VirtualKeyCode::Numpad8 |
-          VirtualKeyCode::K => try_move_player(0, -1, &mut gs.ecs),
-
-          VirtualKeyCode::Down | 
-          VirtualKeyCode::Numpad2 |
-          VirtualKeyCode::J => try_move_player(0, 1, &mut gs.ecs),
-          
-          // 斜め移動
-          VirtualKeyCode::Numpad9 |
-          VirtualKeyCode::Y => try_move_player(1, -1, &mut gs.ecs),
-
-          VirtualKeyCode::Numpad7 |
-          VirtualKeyCode::U => try_move_player(-1, -1, &mut gs.ecs),
-
-          VirtualKeyCode::Numpad3 |
-          VirtualKeyCode::N => try_move_player(1, 1, &mut gs.ecs),
-
-          VirtualKeyCode::Numpad1 |
-          VirtualKeyCode::B => try_move_player(-1, 1, &mut gs.ecs),
-
-          _ => { return RunState::AwaitingInput } // anything else
-      },
+/// プレイヤーが下り階段の上にいればtrue
+pub fn try_next_level(ecs: &mut World) -> bool {
+  let player_pos = ecs.fetch::<Point>();
+  let map = ecs.fetch::<Map>();
+  let player_idx = map.xy_idx(player_pos.x, player_pos.y);
+  if map.tiles[player_idx] == TileType::DownStairs {
+    true
+  } else {
+    let mut gamelog = ecs.fetch_mut::<GameLog>();
+    gamelog.log("There is no way down from here.");
+    false
+  }
+}
+
+/// 足元のアイテムを拾う意思を登録する。実際に拾うのはItemCollectionSystem
+fn get_item(ecs: &mut World) -> bool {
+  let player_pos = ecs.fetch::<Point>();
+  let player_entity = ecs.fetch::<Entity>();
+  let entities = ecs.entities();
+  let items = ecs.read_storage::<Item>();
+  let positions = ecs.read_storage::<Position>();
+  let mut gamelog = ecs.fetch_mut::<GameLog>();
+
+  let target_item = (&entities, &items, &positions).join()
+    .find(|(_, _, position)| position.x == player_pos.x && position.y == player_pos.y)
+    .map(|(item_entity, _, _)| item_entity);
+
+  match target_item {
+    None => {
+      gamelog.log("There is nothing here to pick up.");
+      false
+    }
+    Some(_) if backpack_is_full(ecs) => {
+      gamelog.log("Your backpack is full.");
+      false
+    }
+    Some(item) => {
+      let mut pickup = ecs.write_storage::<WantsToPickupItem>();
+      pickup.insert(*player_entity, WantsToPickupItem{ collected_by: *player_entity, item }).expect("Unable to insert want to pickup");
+      true
+    }
+  }
+}
+
+/// プレイヤーから見えていて、range以内にいる一番近いモンスターの位置
+pub fn nearest_visible_target(ecs: &World, range: i32) -> Option<Point> {
+  let player_pos = ecs.fetch::<Point>();
+  let player_entity = ecs.fetch::<Entity>();
+  let viewsheds = ecs.read_storage::<Viewshed>();
+  let monsters = ecs.read_storage::<Monster>();
+  let positions = ecs.read_storage::<Position>();
+  let visible = &viewsheds.get(*player_entity)?.visible_tiles;
+
+  (&monsters, &positions).join()
+    .map(|(_, pos)| Point::new(pos.x, pos.y))
+    .filter(|pos| visible.contains(pos))
+    .map(|pos| (pos, rltk::DistanceAlg::Pythagoras.distance2d(*player_pos, pos)))
+    .filter(|(_, distance)| *distance <= range as f32)
+    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    .map(|(pos, _)| pos)
+}
+
+/// バックパックに入るアイテムの数。メニューやコマンドでアイテムを a から z の1文字で指すので、26まで
+pub const MAX_BACKPACK_ITEMS: usize = 26;
+
+/// プレイヤーのバックパックがいっぱいならtrue
+fn backpack_is_full(ecs: &World) -> bool {
+  backpack_items(ecs).len() >= MAX_BACKPACK_ITEMS
+}
+
+/// プレイヤーのバックパックの中身。インベントリのメニューと同じ順 (a, b, ...)
+pub fn backpack_items(ecs: &World) -> Vec<Entity> {
+  let player_entity = ecs.fetch::<Entity>();
+  let entities = ecs.entities();
+  let backpack = ecs.read_storage::<InBackpack>();
+
+  (&entities, &backpack).join()
+    .filter(|(_, pack)| pack.owner == *player_entity)
+    .map(|(entity, _)| entity)
+    .collect()
+}
+
+/// 飛び道具で狙えるタイル。視界内で射程range以内、かつ射線の通るもの
+pub fn targetable_tiles(ecs: &World, range: i32) -> Vec<Point> {
+  let player_entity = ecs.fetch::<Entity>();
+  let player_pos = ecs.fetch::<Point>();
+  let viewsheds = ecs.read_storage::<Viewshed>();
+  let map = ecs.fetch::<Map>();
+
+  match viewsheds.get(*player_entity) {
+    None => Vec::new(),
+    Some(viewshed) => viewshed.visible_tiles.iter()
+      .filter(|idx| rltk::DistanceAlg::Pythagoras.distance2d(*player_pos, **idx) <= range as f32)
+      .filter(|idx| map.has_line_of_fire(*player_pos, **idx))
+      .copied()
+      .collect()
+  }
+}
+
+/// 行動した分のエネルギーを使い、プレイヤーの番を終える
+fn end_player_turn(ecs: &mut World, cost: i32) {
+  let player_entity = *ecs.fetch::<Entity>();
+  if let Some(initiative) = ecs.write_storage::<Initiative>().get_mut(player_entity) {
+    initiative.spend(cost);
   }
+  ecs.write_storage::<MyTurn>().remove(player_entity);
+}
+
+/// プレイヤーがslotに装備しているもの
+pub fn equipped_item(ecs: &World, slot: EquipmentSlot) -> Option<Entity> {
+  let player_entity = ecs.fetch::<Entity>();
+  let entities = ecs.entities();
+  let equipped = ecs.read_storage::<Equipped>();
+  (&entities, &equipped).join()
+    .find(|(_, item)| item.owner == *player_entity && item.slot == slot)
+    .map(|(entity, _)| entity)
+}
+
+/// プレイヤーの、まだ伸ばし方を選んでいないレベルの数
+pub fn pending_level_ups(ecs: &World) -> i32 {
+  let player_entity = ecs.fetch::<Entity>();
+  ecs.read_storage::<Experience>().get(*player_entity).map_or(0, |exp| exp.pending_level_ups)
+}
+
+/// 上がったレベル1つ分、選んだものを伸ばす
+fn apply_level_up(ecs: &mut World, choice: LevelUpChoice) {
+  let player_entity = *ecs.fetch::<Entity>();
+  let mut experience = ecs.write_storage::<Experience>();
+  let mut combat_stats = ecs.write_storage::<CombatStats>();
+  let mut attributes = ecs.write_storage::<Attributes>();
+  let mut skills = ecs.write_storage::<Skills>();
+  let (exp, stats, attributes, skills) = match (experience.get_mut(player_entity), combat_stats.get_mut(player_entity),
+    attributes.get_mut(player_entity), skills.get_mut(player_entity)) {
+    (Some(exp), Some(stats), Some(attributes), Some(skills)) => (exp, stats, attributes, skills),
+    _ => return
+  };
+  exp.pending_level_ups -= 1;
+  // 能力値は2上がらないとボーナスが変わらないので、関係する熟練度も一緒に伸ばす
+  let message = match choice {
+    LevelUpChoice::Might => {
+      attributes.might += 1;
+      skills.melee += 1;
+      "You feel mightier."
+    }
+    LevelUpChoice::Fitness => {
+      attributes.fitness += 1;
+      stats.max_hp += LEVEL_UP_FITNESS_HP;
+      stats.hp += LEVEL_UP_FITNESS_HP;
+      "You feel more vigorous."
+    }
+    LevelUpChoice::Quickness => {
+      attributes.quickness += 1;
+      skills.defense += 1;
+      "You feel quicker."
+    }
+  };
+  ecs.fetch_mut::<GameLog>().log(message);
+}
+
+/// コマンドを実行して次の状態を返す。
+/// ターンを使わなかった (できなかった) ときはAwaitingInputのまま。
+/// 行動によって重さが違い、重い行動のあとは次の番が回ってくるまでに時間がかかる
+pub fn perform_command(ecs: &mut World, command: Command) -> RunState {
+  // レベルの伸ばし方を選ぶまでは、ほかの行動はできない
+  let choosing = pending_level_ups(ecs) > 0;
+  if choosing != matches!(command, Command::LevelUp { .. }) {
+    return RunState::AwaitingInput;
+  }
+
+  let cost = match command {
+    // 離れたマスへ飛んだり、自分を攻撃したりはできない
+    Command::Move { delta_x, delta_y } if !is_step(delta_x, delta_y) => return RunState::AwaitingInput,
+    Command::Move { delta_x, delta_y } => try_move_player(delta_x, delta_y, ecs),
+    Command::Wait => WAIT_COST,
+    Command::PickUp => {
+      if !get_item(ecs) {
+        return RunState::AwaitingInput;
+      }
+      PICKUP_COST
+    }
+    Command::Descend => {
+      if try_next_level(ecs) {
+        end_player_turn(ecs, MOVE_COST);
+        return RunState::NextLevel;
+      }
+      return RunState::AwaitingInput;
+    }
+    Command::UseItem { slot, target } => {
+      let item = match backpack_items(ecs).get(slot) {
+        Some(item) => *item,
+        None => return RunState::AwaitingInput
+      };
+      // 飛び道具は狙える場所にしか使えない。そうでないアイテムは自分に使う
+      let range = ecs.read_storage::<Ranged>().get(item).map(|ranged| ranged.range);
+      let target = match range {
+        None => None,
+        Some(range) => match target {
+          Some(target) if targetable_tiles(ecs, range).contains(&target) => Some(target),
+          _ => {
+            ecs.fetch_mut::<GameLog>().log("That target is out of reach.");
+            return RunState::AwaitingInput;
+          }
+        }
+      };
+      let player_entity = *ecs.fetch::<Entity>();
+      let mut intent = ecs.write_storage::<WantsToUseItem>();
+      intent.insert(player_entity, WantsToUseItem{ item, target }).expect("Unable to insert intent");
+      USE_ITEM_COST
+    }
+    Command::DropItem { slot } => {
+      let item = match backpack_items(ecs).get(slot) {
+        Some(item) => *item,
+        None => return RunState::AwaitingInput
+      };
+      let player_entity = *ecs.fetch::<Entity>();
+      let mut intent = ecs.write_storage::<WantsToDropItem>();
+      intent.insert(player_entity, WantsToDropItem{ item }).expect("Unable to insert intent");
+      PICKUP_COST
+    }
+    Command::Unequip { slot } => {
+      let item = match equipped_item(ecs, slot) {
+        Some(item) => item,
+        None => return RunState::AwaitingInput
+      };
+      // 外したものはバックパックに戻すので、空きがいる
+      if backpack_is_full(ecs) {
+        ecs.fetch_mut::<GameLog>().log("Your backpack is full.");
+        return RunState::AwaitingInput;
+      }
+      let player_entity = *ecs.fetch::<Entity>();
+      let mut intent = ecs.write_storage::<WantsToRemoveItem>();
+      intent.insert(player_entity, WantsToRemoveItem{ item }).expect("Unable to insert intent");
+      USE_ITEM_COST
+    }
+    Command::LevelUp { choice } => {
+      // ターンは使わない。スケジューラに戻ると、まだ選ぶものがあればまたLevelUpになる
+      apply_level_up(ecs, choice);
+      return RunState::Ticking;
+    }
+  };
+  end_player_turn(ecs, cost);
   RunState::PlayerTurn
-}
\ No newline at end of file
+}
+
+/// 移動キー (矢印、テンキー、viキー) を方向 (delta_x, delta_y) に変換する。
+/// ターゲット選択などのカーソル移動でも同じキーを使う
+pub fn key_to_direction(key: VirtualKeyCode) -> Option<(i32, i32)> {
+  match key {
+    VirtualKeyCode::Left |
+    VirtualKeyCode::Numpad4 |
+    VirtualKeyCode::H => Some((-1, 0)),
+
+    VirtualKeyCode::Right |
+    VirtualKeyCode::Numpad6 |
+    VirtualKeyCode::L => Some((1, 0)),
+
+    VirtualKeyCode::Up |
+    VirtualKeyCode::Numpad8 |
+    VirtualKeyCode::K => Some((0, -1)),
+
+    VirtualKeyCode::Down |
+    VirtualKeyCode::Numpad2 |
+    VirtualKeyCode::J => Some((0, 1)),
+
+    // 斜め移動
+    VirtualKeyCode::Numpad9 |
+    VirtualKeyCode::Y => Some((1, -1)),
+
+    VirtualKeyCode::Numpad7 |
+    VirtualKeyCode::U => Some((-1, -1)),
+
+    VirtualKeyCode::Numpad3 |
+    VirtualKeyCode::N => Some((1, 1)),
+
+    VirtualKeyCode::Numpad1 |
+    VirtualKeyCode::B => Some((-1, 1)),
+
+    _ => None
+  }
+}
+
+/// 自動移動を始める。始められなければ理由をログに出す
+fn start_auto_move(gs: &mut State, kind: AutoMoveKind) -> RunState {
+  match AutoMove::start(&gs.ecs, kind) {
+    Ok(auto_move) => gs.auto_move = Some(auto_move),
+    Err(reason) => gs.ecs.write_resource::<GameLog>().log(reason)
+  }
+  RunState::AwaitingInput
+}
+
+/// マウスでクリックしたタイル。見たことのあるマップ上のタイルだけ
+fn clicked_tile(gs: &State, ctx: &Rltk) -> Option<usize> {
+  if !ctx.left_click {
+    return None;
+  }
+  let map = gs.ecs.fetch::<Map>();
+  let (x, y) = ctx.mouse_pos();
+  if x < 0 || x >= map.width || y < 0 || y >= map.height {
+    return None;
+  }
+  let idx = map.xy_idx(x, y);
+  if map.revealed_tiles[idx] { Some(idx) } else { None }
+}
+
+/// キー入力をコマンドに変換して実行する。
+/// メニューを開く、ログをスクロールするなど、ゲームの中の行動でないものはここで処理する
+pub fn player_input(gs: &mut State, ctx: &mut Rltk) -> RunState {
+  // クリックしたタイルまで歩く
+  if let Some(destination) = clicked_tile(gs, ctx) {
+    return start_auto_move(gs, AutoMoveKind::Travel{ destination });
+  }
+
+  let key = match ctx.key {
+    None => return RunState::AwaitingInput, // 何も起こらない
+    Some(key) => key
+  };
+
+  let command = match key_to_direction(key) {
+    Some((delta_x, delta_y)) => Command::Move{ delta_x, delta_y },
+    None => match key {
+      // 階段を降りる
+      VirtualKeyCode::Period => Command::Descend,
+
+      // その場で1ターン待つ
+      VirtualKeyCode::Numpad5 |
+      VirtualKeyCode::Space => Command::Wait,
+
+      // 自動探索。何か見つけるまで、まだ行っていない場所へ歩き続ける
+      VirtualKeyCode::O => return start_auto_move(gs, AutoMoveKind::Explore),
+
+      // 見つけている下り階段まで歩く
+      VirtualKeyCode::T => {
+        return match known_stairs(&gs.ecs) {
+          Some(destination) => start_auto_move(gs, AutoMoveKind::Travel{ destination }),
+          None => {
+            gs.ecs.write_resource::<GameLog>().log("You don't know where the stairs are.");
+            RunState::AwaitingInput
+          }
+        };
+      }
+
+      // 調べるモード。カーソルは自分の位置から始める
+      VirtualKeyCode::X => {
+        let player_pos = *gs.ecs.fetch::<Point>();
+        let mut cursor = gs.ecs.write_resource::<gui::TargetingCursor>();
+        cursor.pos = player_pos;
+        cursor.last_mouse = None;
+        return RunState::LookAround;
+      }
+
+      // アイテム
+      VirtualKeyCode::G => Command::PickUp,
+      VirtualKeyCode::I => return RunState::ShowInventory,
+      VirtualKeyCode::D => return RunState::ShowDropItem,
+      VirtualKeyCode::C => return RunState::ShowCharacter,
+
+      // セーブしてメインメニューに戻る
+      VirtualKeyCode::Escape => return RunState::SaveGame,
+
+      // ログのスクロール。ターンは消費しない
+      VirtualKeyCode::PageUp => {
+        gs.ecs.write_resource::<GameLog>().scroll_up(1, gui::LOG_LINES);
+        return RunState::AwaitingInput;
+      }
+
+      VirtualKeyCode::PageDown => {
+        gs.ecs.write_resource::<GameLog>().scroll_down(1);
+        return RunState::AwaitingInput;
+      }
+
+      _ => return RunState::AwaitingInput // anything else
+    }
+  };
+
+  // リプレイに残るよう、State::performを通して実行する
+  gs.perform(command)
+}
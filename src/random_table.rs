@@ -0,0 +1,66 @@
+use rltk::RandomNumberGenerator;
+
+/// 重み付きのくじ。重みの大きい名前ほど当たりやすい
+#[derive(Default)]
+pub struct RandomTable {
+  entries : Vec<(String, i32)>,
+  total_weight : i32
+}
+
+impl RandomTable {
+  pub fn new() -> RandomTable {
+    RandomTable::default()
+  }
+
+  /// 重み0以下のものは足さない (その階層では出ない)
+  pub fn add<S : ToString>(mut self, name : S, weight : i32) -> RandomTable {
+    if weight > 0 {
+      self.total_weight += weight;
+      self.entries.push((name.to_string(), weight));
+    }
+    self
+  }
+
+  /// くじを1回引く。何も入っていなければNone
+  pub fn roll(&self, rng : &mut RandomNumberGenerator) -> Option<&str> {
+    if self.total_weight == 0 {
+      return None;
+    }
+    let mut roll = rng.roll_dice(1, self.total_weight) - 1;
+    for (name, weight) in self.entries.iter() {
+      if roll < *weight {
+        return Some(name);
+      }
+      roll -= weight;
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn empty_table_rolls_nothing() {
+    let mut rng = RandomNumberGenerator::seeded(1);
+    assert_eq!(RandomTable::new().roll(&mut rng), None);
+    assert_eq!(RandomTable::new().add("Goblin", 0).add("Orc", -3).roll(&mut rng), None);
+  }
+
+  #[test]
+  fn rolls_follow_the_weights() {
+    let mut rng = RandomNumberGenerator::seeded(1);
+    let table = RandomTable::new().add("Goblin", 1).add("Nothing", 0).add("Orc", 3);
+    let mut orcs = 0;
+    for _ in 0..4000 {
+      match table.roll(&mut rng) {
+        Some("Orc") => orcs += 1,
+        Some("Goblin") => {}
+        other => panic!("unexpected roll {:?}", other)
+      }
+    }
+    // 3/4が当たるはず
+    assert!((2800..=3200).contains(&orcs), "{} orcs out of 4000", orcs);
+  }
+}
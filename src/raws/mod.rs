@@ -0,0 +1,59 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+mod raw_structs;
+use raw_structs::Raws;
+mod rawmaster;
+pub use rawmaster::RawMaster;
+
+/// 定義ファイルの場所を指定する環境変数。ビルドし直さずに定義を試すときに使う
+pub const RAWS_ENV : &str = "RLRUST_RAWS";
+
+/// 実行ファイルのあるディレクトリから見た定義ファイルの場所
+const RAWS_RELATIVE_PATH : &str = "raws/spawns.json";
+
+/// ビルド時に埋め込んだ定義。ファイルが見つからないときに使う。
+/// cargo runなら、raws/spawns.jsonを書き換えればビルドし直されてこちらに入る
+pub const BUNDLED_RAWS : &str = include_str!("../../raws/spawns.json");
+
+/// 読み込んだ定義。起動時に1回だけ読んで、あとはどこからでも使う
+static RAWS : OnceLock<RawMaster> = OnceLock::new();
+
+/// 定義を読む。どこから読んだかと中身を返す。
+/// 環境変数RLRUST_RAWSがあればそのファイル、なければ実行ファイルの隣のraws/spawns.json、
+/// それもなければ埋め込んだ定義。起動したディレクトリには左右されない
+fn read_raws() -> Result<(String, String), String> {
+  let path = match std::env::var_os(RAWS_ENV) {
+    Some(path) => Some(PathBuf::from(path)),
+    None => std::env::current_exe().ok()
+      .and_then(|exe| exe.parent().map(|dir| dir.join(RAWS_RELATIVE_PATH)))
+      .filter(|path| path.exists())
+  };
+  match path {
+    Some(path) => {
+      let name = path.display().to_string();
+      let text = fs::read_to_string(&path).map_err(|e| format!("Unable to read spawn definitions {}: {}", name, e))?;
+      Ok((name, text))
+    }
+    None => Ok(("bundled spawn definitions".to_string(), BUNDLED_RAWS.to_string()))
+  }
+}
+
+/// 定義を読んで検査する。もう読んであれば何もしない。
+/// JSONとして読めないときや、中身がおかしいときは、どこが悪いかを書いたErrを返す
+pub fn load_raws() -> Result<(), String> {
+  if RAWS.get().is_some() {
+    return Ok(());
+  }
+  let (path, text) = read_raws()?;
+  let raws : Raws = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+  let master = RawMaster::new(raws)
+    .map_err(|errors| format!("{}: invalid spawn definitions: {}", path, errors.join("; ")))?;
+  let _ = RAWS.set(master);
+  Ok(())
+}
+
+/// 読み込み済みの定義。load_rawsより前に呼ぶとpanicする
+pub fn raws() -> &'static RawMaster {
+  RAWS.get().expect("Spawn definitions are not loaded")
+}
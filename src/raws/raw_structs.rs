@@ -0,0 +1,147 @@
+use serde::Deserialize;
+
+// raws/spawns.jsonの中身をそのまま写した型。
+// 知らないキーは書き間違いなのでエラーにする (deny_unknown_fields)
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Raws {
+  pub monsters : Vec<RawMonster>,
+  pub items : Vec<RawItem>,
+  pub spawn_table : Vec<RawSpawnEntry>
+}
+
+/// 出現表の1行。階層depthでの重みは weight + weight_per_depth * (depth - 1)。
+/// min_depthからmax_depthまでの階層にしか出ない
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RawSpawnEntry {
+  pub name : String,
+  pub weight : i32,
+  #[serde(default)]
+  pub weight_per_depth : i32,
+  #[serde(default = "default_min_depth")]
+  pub min_depth : i32,
+  pub max_depth : Option<i32>
+}
+
+fn default_min_depth() -> i32 {
+  1
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RawMonster {
+  pub name : String,
+  /// 1文字
+  pub glyph : String,
+  /// "#RRGGBB"
+  pub fg : String,
+  pub vision_range : i32,
+  /// 行動の速さ。プレイヤーは100
+  pub speed : i32,
+  /// 階層1での強さ。深い階層ではこれより強くなる
+  pub stats : RawStats,
+  /// 書かなければ全部10 (人並み)
+  #[serde(default)]
+  pub attributes : RawAttributes,
+  #[serde(default)]
+  pub skills : RawSkills,
+  /// 素手で戦うときの攻撃 (牙や爪)
+  pub attack : RawAttack,
+  /// 階層1で倒したときにもらえる経験値。深い階層では階層の数だけ倍になる
+  pub xp : i32,
+  #[serde(default)]
+  pub ai : RawAi
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RawStats {
+  pub max_hp : i32
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct RawAttributes {
+  pub might : i32,
+  pub fitness : i32,
+  pub quickness : i32,
+  pub intelligence : i32
+}
+
+impl Default for RawAttributes {
+  fn default() -> RawAttributes {
+    RawAttributes{ might: 10, fitness: 10, quickness: 10, intelligence: 10 }
+  }
+}
+
+/// 技能の熟練度。書かなければ0
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct RawSkills {
+  pub melee : i32,
+  pub defense : i32
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RawAttack {
+  /// "1d6" や "2d4+1" のようなダイス
+  pub damage : String,
+  #[serde(default)]
+  pub hit_bonus : i32
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RawAi {
+  /// 眠った状態で置かれることがある
+  #[serde(default)]
+  pub sleeps : bool,
+  /// 弱ると逃げる
+  #[serde(default)]
+  pub flees : bool
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RawItem {
+  pub name : String,
+  pub glyph : String,
+  pub fg : String,
+  /// 使うとなくなる
+  #[serde(default)]
+  pub consumable : bool,
+  #[serde(default)]
+  pub effects : RawEffects,
+  /// 装備品なら、付ける場所とボーナス
+  pub equip : Option<RawEquip>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RawEquip {
+  /// "melee" か "shield"
+  pub slot : String,
+  /// 武器のダメージのダイス。"melee"の装備には要る
+  pub damage : Option<String>,
+  #[serde(default)]
+  pub hit_bonus : i32,
+  /// 上がるアーマークラス
+  #[serde(default)]
+  pub defense_bonus : i32
+}
+
+/// アイテムを使ったときの効果。書いたものだけが付く
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RawEffects {
+  pub healing : Option<i32>,
+  /// 離れた相手に使える。値は射程
+  pub ranged : Option<i32>,
+  pub damage : Option<i32>,
+  pub area_of_effect : Option<i32>,
+  /// 混乱させるターン数
+  pub confusion : Option<i32>
+}
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use rltk::{RGB, RandomNumberGenerator};
+use specs::prelude::*;
+use specs::saveload::{MarkedBuilder, SimpleMarker};
+use crate::random_table::RandomTable;
+use crate::{SerializeMe, CombatStats, Renderable, Name, Position, Viewshed, Monster, BlocksTile, Item, Consumable,
+  ProvidesHealing, Ranged, InflictsDamage, AreaOfEffect, Confusion, Initiative, Brain, AiState,
+  EquipmentSlot, Equippable, MeleeWeapon, DefenseBonus, ExperienceReward, Attributes, Skills};
+use super::raw_structs::*;
+
+/// 名前で引いたときに、どちらの一覧の何番目か
+enum SpawnType {
+  Monster(usize),
+  Item(usize)
+}
+
+/// 読み込んで検査済みの定義と、名前からの索引
+pub struct RawMaster {
+  raws : Raws,
+  index : HashMap<String, SpawnType>
+}
+
+/// 1文字の文字列をフォントの文字に変える
+fn parse_glyph(glyph : &str) -> Option<rltk::FontCharType> {
+  let mut chars = glyph.chars();
+  match (chars.next(), chars.next()) {
+    (Some(c), None) => Some(rltk::to_cp437(c)),
+    _ => None
+  }
+}
+
+/// 名前・文字・色の共通の検査
+fn check_appearance(errors : &mut Vec<String>, what : &str, name : &str, glyph : &str, fg : &str) {
+  if name.trim().is_empty() {
+    errors.push(format!("a {} has an empty name", what));
+  }
+  if parse_glyph(glyph).is_none() {
+    errors.push(format!("{} '{}': glyph must be a single character, got \"{}\"", what, name, glyph));
+  }
+  if RGB::from_hex(fg).is_err() {
+    errors.push(format!("{} '{}': fg must be a color like \"#FF0000\", got \"{}\"", what, name, fg));
+  }
+}
+
+/// "1d6" や "2d4+1" を (ダイスの数, 面の数, ボーナス) にする
+fn parse_dice(dice : &str) -> Option<(i32, i32, i32)> {
+  let (n_dice, rest) = dice.trim().split_once('d')?;
+  let (die_type, bonus) = match rest.find(['+', '-']) {
+    Some(i) => (&rest[..i], rest[i..].trim_start_matches('+').parse().ok()?),
+    None => (rest, 0)
+  };
+  let n_dice : i32 = n_dice.parse().ok()?;
+  let die_type : i32 = die_type.parse().ok()?;
+  if n_dice <= 0 || die_type <= 0 {
+    return None;
+  }
+  Some((n_dice, die_type, bonus))
+}
+
+fn check_dice(errors : &mut Vec<String>, what : &str, name : &str, field : &str, dice : &str) {
+  if parse_dice(dice).is_none() {
+    errors.push(format!("{} '{}': {} must be dice like \"1d6\" or \"2d4+1\", got \"{}\"", what, name, field, dice));
+  }
+}
+
+fn check_positive(errors : &mut Vec<String>, what : &str, name : &str, field : &str, value : i32) {
+  if value <= 0 {
+    errors.push(format!("{} '{}': {} must be greater than 0, got {}", what, name, field, value));
+  }
+}
+
+fn check_monster(errors : &mut Vec<String>, monster : &RawMonster) {
+  let name = &monster.name;
+  check_appearance(errors, "monster", name, &monster.glyph, &monster.fg);
+  check_positive(errors, "monster", name, "vision_range", monster.vision_range);
+  check_positive(errors, "monster", name, "speed", monster.speed);
+  check_positive(errors, "monster", name, "stats.max_hp", monster.stats.max_hp);
+  if monster.xp < 0 {
+    errors.push(format!("monster '{}': xp must not be negative, got {}", name, monster.xp));
+  }
+  let attributes = &monster.attributes;
+  let scores = [("might", attributes.might), ("fitness", attributes.fitness),
+    ("quickness", attributes.quickness), ("intelligence", attributes.intelligence)];
+  for (field, value) in scores.iter() {
+    check_positive(errors, "monster", name, &format!("attributes.{}", field), *value);
+  }
+  if monster.skills.melee < 0 || monster.skills.defense < 0 {
+    errors.push(format!("monster '{}': skills must not be negative", name));
+  }
+  check_dice(errors, "monster", name, "attack.damage", &monster.attack.damage);
+}
+
+fn check_item(errors : &mut Vec<String>, item : &RawItem) {
+  let name = &item.name;
+  let effects = &item.effects;
+  check_appearance(errors, "item", name, &item.glyph, &item.fg);
+
+  let amounts = [("healing", effects.healing), ("ranged", effects.ranged), ("damage", effects.damage),
+    ("area_of_effect", effects.area_of_effect), ("confusion", effects.confusion)];
+  for (field, value) in amounts.iter() {
+    if let Some(value) = value {
+      check_positive(errors, "item", name, &format!("effects.{}", field), *value);
+    }
+  }
+
+  let has_effects = amounts.iter().any(|(_, value)| value.is_some());
+  match &item.equip {
+    None if !has_effects => errors.push(format!("item '{}': has neither effects nor equip, the item would do nothing", name)),
+    None => {}
+    Some(equip) => {
+      // 装備品は使うと装備するので、使ったときの効果は持てない
+      if has_effects || item.consumable {
+        errors.push(format!("item '{}': equipment can't have effects or be consumable", name));
+      }
+      if EquipmentSlot::from_name(&equip.slot).is_none() {
+        let slots : Vec<&str> = EquipmentSlot::ALL.iter().map(|slot| slot.name()).collect();
+        errors.push(format!("item '{}': unknown equip.slot \"{}\", expected one of {}", name, equip.slot, slots.join(", ")));
+      }
+      match (&equip.damage, EquipmentSlot::from_name(&equip.slot)) {
+        (Some(damage), Some(EquipmentSlot::Melee)) => check_dice(errors, "item", name, "equip.damage", damage),
+        (None, Some(EquipmentSlot::Melee)) => errors.push(format!("item '{}': a melee weapon needs equip.damage", name)),
+        (Some(_), _) => errors.push(format!("item '{}': only melee equipment can have equip.damage", name)),
+        (None, _) => {}
+      }
+      if equip.hit_bonus < 0 || equip.defense_bonus < 0 {
+        errors.push(format!("item '{}': equip bonuses must not be negative", name));
+      }
+    }
+  }
+  // ダメージと混乱は離れた相手に向けて使うので、射程がいる
+  if effects.ranged.is_none() && (effects.damage.is_some() || effects.confusion.is_some() || effects.area_of_effect.is_some()) {
+    errors.push(format!("item '{}': damage, confusion and area_of_effect need a ranged value", name));
+  }
+}
+
+fn check_spawn_entry(errors : &mut Vec<String>, entry : &RawSpawnEntry, index : &HashMap<String, SpawnType>) {
+  let name = &entry.name;
+  if !index.contains_key(name) {
+    errors.push(format!("spawn_table: '{}' is not a defined monster or item", name));
+  }
+  if entry.weight < 0 {
+    errors.push(format!("spawn_table '{}': weight must not be negative, got {}", name, entry.weight));
+  }
+  if entry.min_depth < 1 {
+    errors.push(format!("spawn_table '{}': min_depth must be 1 or more, got {}", name, entry.min_depth));
+  }
+  if let Some(max_depth) = entry.max_depth {
+    if max_depth < entry.min_depth {
+      errors.push(format!("spawn_table '{}': max_depth {} is less than min_depth {}", name, max_depth, entry.min_depth));
+    }
+  }
+}
+
+impl RawMaster {
+  /// 定義を検査して索引を作る。おかしなところがあれば全部まとめてErrで返す
+  pub fn new(raws : Raws) -> Result<RawMaster, Vec<String>> {
+    let mut errors = Vec::new();
+    let mut index = HashMap::new();
+
+    for (i, monster) in raws.monsters.iter().enumerate() {
+      check_monster(&mut errors, monster);
+      if index.insert(monster.name.clone(), SpawnType::Monster(i)).is_some() {
+        errors.push(format!("'{}' is defined more than once", monster.name));
+      }
+    }
+    for (i, item) in raws.items.iter().enumerate() {
+      check_item(&mut errors, item);
+      if index.insert(item.name.clone(), SpawnType::Item(i)).is_some() {
+        errors.push(format!("'{}' is defined more than once", item.name));
+      }
+    }
+    for entry in raws.spawn_table.iter() {
+      check_spawn_entry(&mut errors, entry, &index);
+    }
+    if raws.monsters.is_empty() {
+      errors.push("there are no monsters".to_string());
+    }
+    if raws.items.is_empty() {
+      errors.push("there are no items".to_string());
+    }
+    if raws.spawn_table.is_empty() {
+      errors.push("spawn_table is empty".to_string());
+    }
+
+    if errors.is_empty() { Ok(RawMaster{ raws, index }) } else { Err(errors) }
+  }
+
+  /// 階層depthで出てくるものの重み付きくじ
+  pub fn spawn_table_for_depth(&self, depth : i32) -> RandomTable {
+    self.raws.spawn_table.iter()
+      .filter(|entry| depth >= entry.min_depth && entry.max_depth.is_none_or(|max_depth| depth <= max_depth))
+      .fold(RandomTable::new(), |table, entry| table.add(&entry.name, entry.weight + entry.weight_per_depth * (depth - 1)))
+  }
+
+  /// 名前がモンスターの定義か
+  pub fn is_monster(&self, name : &str) -> bool {
+    matches!(self.index.get(name), Some(SpawnType::Monster(_)))
+  }
+
+  /// 名前の定義を(x, y)に置く。indexはモンスターの名前に付ける通し番号、depthは階層。
+  /// そんな名前の定義がなければNone
+  pub fn spawn_named(&self, ecs : &mut World, name : &str, x : i32, y : i32, index : usize, depth : i32) -> Option<Entity> {
+    match self.index.get(name)? {
+      SpawnType::Monster(i) => Some(spawn_monster(ecs, &self.raws.monsters[*i], x, y, index, depth)),
+      SpawnType::Item(i) => Some(spawn_item(ecs, &self.raws.items[*i], x, y))
+    }
+  }
+}
+
+/// ダイスの文字列から武器を作る。ダイスは検査済みであること
+fn melee_weapon(damage : &str, hit_bonus : i32) -> MeleeWeapon {
+  let (damage_n_dice, damage_die_type, damage_bonus) = parse_dice(damage).expect("Dice was validated");
+  MeleeWeapon{ damage_n_dice, damage_die_type, damage_bonus, hit_bonus }
+}
+
+/// 深い階層ほどモンスターは打たれ強く、攻撃も当たりやすく、よけやすくなる
+fn spawn_monster(ecs : &mut World, monster : &RawMonster, x : i32, y : i32, index : usize, depth : i32) -> Entity {
+  let max_hp = monster.stats.max_hp + (depth - 1) * 3;
+  let skills = Skills{
+    melee: monster.skills.melee + (depth - 1) / 2,
+    defense: monster.skills.defense + (depth - 1) / 3
+  };
+  let attributes = &monster.attributes;
+  // 全員が同時に動き出さないよう、最初のエネルギーをばらつかせる。
+  // 眠るモンスターは半分が眠っていて、残りはうろついている
+  let (energy, state) = {
+    let mut rng = ecs.write_resource::<RandomNumberGenerator>();
+    let energy = -rng.roll_dice(1, 100);
+    let state = if rng.roll_dice(1, 2) == 1 && monster.ai.sleeps { AiState::Idle } else { AiState::Wandering };
+    (energy, state)
+  };
+
+  ecs.create_entity()
+    .with(Position{x, y})
+    .with(Renderable{
+      glyph: parse_glyph(&monster.glyph).expect("Glyph was validated"),
+      fg: RGB::from_hex(&monster.fg).expect("Color was validated"),
+      bg: RGB::named(rltk::BLACK),
+      render_order: 1
+    })
+    .with(Viewshed{
+      visible_tiles: Vec::new(),
+      range: monster.vision_range,
+      dirty: true
+    })
+    .with(Monster{})
+    .with(Name{ name: format!("{} #{}", monster.name, index)})
+    .with(BlocksTile{})
+    .with(CombatStats{ max_hp, hp: max_hp })
+    .with(Attributes{
+      might: attributes.might,
+      fitness: attributes.fitness,
+      quickness: attributes.quickness,
+      intelligence: attributes.intelligence
+    })
+    .with(skills)
+    .with(melee_weapon(&monster.attack.damage, monster.attack.hit_bonus))
+    .with(Initiative{ energy, speed: monster.speed })
+    .with(Brain{ state, last_seen_player: None, flees: monster.ai.flees })
+    .with(ExperienceReward{ xp: monster.xp * depth })
+    .marked::<SimpleMarker<SerializeMe>>()
+    .build()
+}
+
+fn spawn_item(ecs : &mut World, item : &RawItem, x : i32, y : i32) -> Entity {
+  let effects = &item.effects;
+  let mut builder = ecs.create_entity()
+    .with(Position{ x, y })
+    .with(Renderable{
+      glyph: parse_glyph(&item.glyph).expect("Glyph was validated"),
+      fg: RGB::from_hex(&item.fg).expect("Color was validated"),
+      bg: RGB::named(rltk::BLACK),
+      render_order: 2
+    })
+    .with(Name{ name: item.name.clone() })
+    .with(Item{});
+
+  if item.consumable {
+    builder = builder.with(Consumable{});
+  }
+  if let Some(heal_amount) = effects.healing {
+    builder = builder.with(ProvidesHealing{ heal_amount });
+  }
+  if let Some(range) = effects.ranged {
+    builder = builder.with(Ranged{ range });
+  }
+  if let Some(damage) = effects.damage {
+    builder = builder.with(InflictsDamage{ damage });
+  }
+  if let Some(radius) = effects.area_of_effect {
+    builder = builder.with(AreaOfEffect{ radius });
+  }
+  if let Some(turns) = effects.confusion {
+    builder = builder.with(Confusion{ turns });
+  }
+  if let Some(equip) = &item.equip {
+    let slot = EquipmentSlot::from_name(&equip.slot).expect("Slot was validated");
+    builder = builder.with(Equippable{ slot });
+    if let Some(damage) = &equip.damage {
+      builder = builder.with(melee_weapon(damage, equip.hit_bonus));
+    }
+    if equip.defense_bonus > 0 {
+      builder = builder.with(DefenseBonus{ defense: equip.defense_bonus });
+    }
+  }
+
+  builder.marked::<SimpleMarker<SerializeMe>>().build()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RAT : &str = r##"{ "name": "Rat", "glyph": "r", "fg": "#FF0000", "vision_range": 6, "speed": 100,
+    "stats": { "max_hp": 4 }, "attack": { "damage": "1d2" }, "xp": 5 }"##;
+  const BREAD : &str = r##"{ "name": "Bread", "glyph": "%", "fg": "#FFFF00", "consumable": true, "effects": { "healing": 2 } }"##;
+
+  /// 小さな定義を組み立てて検査する
+  fn master_from(monsters : &[&str], items : &[&str], spawn_table : &str) -> Result<RawMaster, Vec<String>> {
+    let json = format!(r#"{{ "monsters": [{}], "items": [{}], "spawn_table": {} }}"#, monsters.join(","), items.join(","), spawn_table);
+    RawMaster::new(serde_json::from_str(&json).unwrap())
+  }
+
+  fn assert_has_error(errors : &[String], expected : &str) {
+    assert!(errors.iter().any(|e| e.contains(expected)), "no error containing \"{}\" in {:?}", expected, errors);
+  }
+
+  #[test]
+  fn parses_dice() {
+    assert_eq!(parse_dice("1d6"), Some((1, 6, 0)));
+    assert_eq!(parse_dice("2d4+1"), Some((2, 4, 1)));
+    assert_eq!(parse_dice("1d8-2"), Some((1, 8, -2)));
+    assert_eq!(parse_dice(" 3d10 "), Some((3, 10, 0)));
+    for dice in ["", "d6", "1d", "0d6", "1d0", "-1d6", "1x6", "1d6+", "1d6+x", "one d6"].iter() {
+      assert_eq!(parse_dice(dice), None, "'{}' should not parse", dice);
+    }
+  }
+
+  #[test]
+  fn accepts_valid_definitions() {
+    assert!(master_from(&[RAT], &[BREAD], r#"[{ "name": "Rat", "weight": 1 }]"#).is_ok());
+  }
+
+  #[test]
+  fn reports_every_bad_definition() {
+    let bad_rat = RAT.replace(r#""glyph": "r""#, r#""glyph": "rat""#).replace("#FF0000", "red").replace(r#""speed": 100"#, r#""speed": 0"#);
+    let useless = r##"{ "name": "Stone", "glyph": "*", "fg": "#FFFFFF" }"##;
+    let errors = master_from(&[&bad_rat, RAT], &[BREAD, useless], r#"[{ "name": "Rat", "weight": 1 }]"#).err().unwrap();
+    assert_has_error(&errors, "monster 'Rat': glyph must be a single character");
+    assert_has_error(&errors, "monster 'Rat': fg must be a color");
+    assert_has_error(&errors, "monster 'Rat': speed must be greater than 0");
+    assert_has_error(&errors, "'Rat' is defined more than once");
+    assert_has_error(&errors, "item 'Stone': has neither effects nor equip");
+  }
+
+  #[test]
+  fn rejects_misspelled_keys() {
+    let misspelled = RAT.replace("vision_range", "vision");
+    let json = format!(r#"{{ "monsters": [{}], "items": [{}], "spawn_table": [] }}"#, misspelled, BREAD);
+    let error = serde_json::from_str::<Raws>(&json).err().unwrap().to_string();
+    assert!(error.contains("unknown field `vision`"), "{}", error);
+  }
+
+  /// depthで1000回くじを引いて、当たった名前を数える
+  fn roll_counts(master : &RawMaster, depth : i32) -> HashMap<String, i32> {
+    let table = master.spawn_table_for_depth(depth);
+    let mut rng = RandomNumberGenerator::seeded(5);
+    let mut counts = HashMap::new();
+    for _ in 0..1000 {
+      if let Some(name) = table.roll(&mut rng) {
+        *counts.entry(name.to_string()).or_insert(0) += 1;
+      }
+    }
+    counts
+  }
+
+  #[test]
+  fn spawn_table_filters_by_depth() {
+    let master = master_from(&[RAT], &[BREAD], r#"[
+      { "name": "Rat", "weight": 5, "max_depth": 2 },
+      { "name": "Bread", "weight": 5, "min_depth": 2 }
+    ]"#).unwrap();
+    let depth_1 = roll_counts(&master, 1);
+    assert_eq!(depth_1.get("Bread"), None);
+    assert_eq!(depth_1.get("Rat"), Some(&1000));
+    let depth_2 = roll_counts(&master, 2);
+    assert!(depth_2.contains_key("Rat") && depth_2.contains_key("Bread"));
+    let depth_3 = roll_counts(&master, 3);
+    assert_eq!(depth_3.get("Rat"), None);
+    assert_eq!(depth_3.get("Bread"), Some(&1000));
+  }
+
+  #[test]
+  fn spawn_table_weights_grow_with_depth() {
+    let master = master_from(&[RAT], &[BREAD], r#"[
+      { "name": "Rat", "weight": 1 },
+      { "name": "Bread", "weight": 1, "weight_per_depth": 2 }
+    ]"#).unwrap();
+    // 階層1では1:1、階層4では1:7
+    let bread_1 = roll_counts(&master, 1)["Bread"];
+    let bread_4 = roll_counts(&master, 4)["Bread"];
+    assert!((400..=600).contains(&bread_1), "{} bread at depth 1", bread_1);
+    assert!((820..=930).contains(&bread_4), "{} bread at depth 4", bread_4);
+  }
+
+  #[test]
+  fn rejects_unknown_spawn_names_and_bad_depths() {
+    let errors = master_from(&[RAT], &[BREAD], r#"[
+      { "name": "Dragon", "weight": 1 },
+      { "name": "Rat", "weight": 1, "min_depth": 3, "max_depth": 2 }
+    ]"#).err().unwrap();
+    assert_has_error(&errors, "'Dragon' is not a defined monster or item");
+    assert_has_error(&errors, "max_depth 2 is less than min_depth 3");
+  }
+
+  #[test]
+  fn bundled_raws_are_valid_and_every_spawn_entry_resolves() {
+    let master = RawMaster::new(serde_json::from_str(crate::raws::BUNDLED_RAWS).unwrap()).unwrap_or_else(|errors| panic!("{}", errors.join("; ")));
+    for entry in master.raws.spawn_table.iter() {
+      assert!(master.index.contains_key(&entry.name), "'{}' is not defined", entry.name);
+    }
+    for depth in 1..=10 {
+      let table = master.spawn_table_for_depth(depth);
+      let mut rng = RandomNumberGenerator::seeded(depth as u64);
+      for _ in 0..100 {
+        let name = table.roll(&mut rng).unwrap_or_else(|| panic!("nothing spawns at depth {}", depth));
+        assert!(master.index.contains_key(name));
+      }
+    }
+  }
+}
@@ -1,5 +1,6 @@
-use crate::TileType;
+use serde::{Serialize, Deserialize};
 
+#[derive(PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct Rect {
   pub x1 : i32,
   pub x2 : i32,
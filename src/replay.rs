@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use super::map_builders::BUILDER_NAMES;
+use super::Command;
+
+/// ウィンドウで遊んだゲームの記録先。新しいゲームを始めるたびに上書きする
+pub const REPLAY_PATH : &str = "./last_game.replay";
+
+/// リプレイファイルの書式のバージョン。書式を変えたら上げる
+const REPLAY_VERSION : u32 = 2;
+
+/// リプレイファイルの1コマンド
+pub struct ReplayCommand {
+  /// ファイルの何行目か。エラーメッセージ用
+  pub line : usize,
+  /// このコマンドを受け付けたときのターン数。再生がずれていないかの確認に使う
+  pub turn : i32,
+  pub command : Command
+}
+
+/// 読み込んだリプレイ。
+/// 同じシードとマップビルダーで新しいゲームを始め、コマンドを順に実行すれば同じゲームになる
+pub struct Replay {
+  pub seed : u64,
+  pub builder_name : Option<String>,
+  pub commands : VecDeque<ReplayCommand>
+}
+
+impl Replay {
+  /// リプレイファイルを読む。書式は
+  /// ```text
+  /// version 2
+  /// seed 12345
+  /// builder random
+  /// turn 0 move 1 0
+  /// turn 1 use a 10 12
+  /// ```
+  /// コマンドの書式はスクリプトと同じ。#から後ろはコメント
+  pub fn load(path : &str) -> Result<Replay, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Unable to read replay {}: {}", path, e))?;
+
+    let mut version = None;
+    let mut seed = None;
+    let mut builder_name = None;
+    let mut commands = VecDeque::new();
+
+    for (i, line) in data.lines().enumerate() {
+      let line_number = i + 1;
+      let error = |message : String| format!("{}:{}: {}", path, line_number, message);
+      let line = line.split('#').next().unwrap().trim();
+      let (key, rest) = match line.split_once(char::is_whitespace) {
+        Some((key, rest)) => (key, rest.trim()),
+        None if line.is_empty() => continue,
+        None => return Err(error(format!("'{}' needs a value", line)))
+      };
+
+      match key {
+        "version" => version = Some(rest.parse::<u32>().map_err(|_| error(format!("invalid version '{}'", rest)))?),
+        "seed" => seed = Some(rest.parse::<u64>().map_err(|_| error(format!("invalid seed '{}'", rest)))?),
+        "builder" => {
+          builder_name = match rest {
+            "random" => None,
+            name if BUILDER_NAMES.contains(&name) => Some(name.to_string()),
+            name => return Err(error(format!("unknown builder '{}'", name)))
+          };
+        }
+        "turn" => {
+          let (turn, command) = rest.split_once(char::is_whitespace).ok_or_else(|| error("missing command".to_string()))?;
+          let turn = turn.parse::<i32>().map_err(|_| error(format!("invalid turn '{}'", turn)))?;
+          let command = command.parse::<Command>().map_err(error)?;
+          commands.push_back(ReplayCommand{ line: line_number, turn, command });
+        }
+        other => return Err(error(format!("unknown entry '{}'", other)))
+      }
+    }
+
+    match version {
+      Some(REPLAY_VERSION) => {}
+      Some(other) => return Err(format!("Replay version {} is not supported (expected {})", other, REPLAY_VERSION)),
+      None => return Err(format!("{}: missing version", path))
+    }
+    let seed = seed.ok_or_else(|| format!("{}: missing seed", path))?;
+
+    Ok(Replay{ seed, builder_name, commands })
+  }
+}
+
+/// 受け付けたコマンドをリプレイファイルに書いていく。
+/// 途中で落ちても残るよう、1コマンドごとにファイルに書き出す
+pub struct ReplayRecorder {
+  file : File
+}
+
+impl ReplayRecorder {
+  pub fn create(path : &str, seed : u64, builder_name : &Option<String>) -> io::Result<ReplayRecorder> {
+    let mut file = File::create(path)?;
+    writeln!(file, "version {}", REPLAY_VERSION)?;
+    writeln!(file, "seed {}", seed)?;
+    writeln!(file, "builder {}", builder_name.as_deref().unwrap_or("random"))?;
+    Ok(ReplayRecorder{ file })
+  }
+
+  pub fn record(&mut self, turn : i32, command : Command) -> io::Result<()> {
+    writeln!(self.file, "turn {} {}", turn, command)?;
+    self.file.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// textをリプレイファイルとして読む
+  fn load_text(name : &str, text : &str) -> Result<Replay, String> {
+    let path = std::env::temp_dir().join(format!("rlrust_{}_{}.replay", name, std::process::id()));
+    let path = path.to_str().unwrap();
+    fs::write(path, text).unwrap();
+    let result = Replay::load(path);
+    let _ = fs::remove_file(path);
+    result
+  }
+
+  fn load_error(name : &str, text : &str) -> String {
+    load_text(name, text).err().expect("the replay should not load")
+  }
+
+  #[test]
+  fn loads_a_valid_replay() {
+    let replay = load_text("valid", "version 2\nseed 42\nbuilder random\nturn 0 move 1 0 # comment\n\nturn 3 wait\n").unwrap();
+    assert_eq!(replay.seed, 42);
+    assert_eq!(replay.builder_name, None);
+    let commands : Vec<(usize, i32, Command)> = replay.commands.iter().map(|entry| (entry.line, entry.turn, entry.command)).collect();
+    assert_eq!(commands, vec![(4, 0, Command::Move{ delta_x: 1, delta_y: 0 }), (6, 3, Command::Wait)]);
+  }
+
+  #[test]
+  fn rejects_an_unsupported_version() {
+    assert!(load_error("old_version", "version 1\nseed 42\n").contains("version 1 is not supported"));
+    assert!(load_error("bad_version", "version two\nseed 42\n").contains("invalid version 'two'"));
+    assert!(load_error("no_version", "seed 42\n").contains("missing version"));
+  }
+
+  #[test]
+  fn rejects_a_bad_header() {
+    assert!(load_error("no_seed", "version 2\n").contains("missing seed"));
+    assert!(load_error("bad_seed", "version 2\nseed -1\n").contains("invalid seed"));
+    assert!(load_error("bad_builder", "version 2\nseed 1\nbuilder castle\n").contains("unknown builder 'castle'"));
+    assert!(load_error("unknown_entry", "version 2\nseed 1\ncolor red\n").contains(":3: unknown entry 'color'"));
+    assert!(load_error("no_value", "version\n").contains("'version' needs a value"));
+  }
+
+  #[test]
+  fn rejects_bad_commands() {
+    assert!(load_error("bad_turn", "version 2\nseed 1\nturn x wait\n").contains("invalid turn 'x'"));
+    assert!(load_error("no_command", "version 2\nseed 1\nturn 3\n").contains("missing command"));
+    assert!(load_error("bad_command", "version 2\nseed 1\nturn 3 fly\n").contains(":3: unknown command 'fly'"));
+  }
+}
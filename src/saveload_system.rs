@@ -0,0 +1,194 @@
+use specs::prelude::*;
+use specs::saveload::{SimpleMarker, SimpleMarkerAllocator, SerializeComponents, DeserializeComponents, MarkedBuilder};
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use super::components::*;
+use super::{Map, GameLog, GameStats, RunState};
+
+/// セーブファイルの置き場所。State::save_pathの初期値
+pub const SAVE_PATH : &str = "./savegame.json";
+
+/// セーブファイルの形式が変わったら上げる。違うバージョンのファイルは読まない
+const SAVE_VERSION : u32 = 7;
+
+/// セーブファイルの先頭に書くヘッダ。この後ろに各コンポーネントのストレージが続く
+#[derive(Serialize, Deserialize)]
+struct SaveHeader {
+  version : u32
+}
+
+// 型ごとにSerializeComponentsを呼ぶマクロ。
+// 保存するコンポーネントを増やしたら、save_gameとload_gameの両方のリストに足すこと
+macro_rules! serialize_individually {
+  ($ecs:expr, $ser:expr, $data:expr, $( $type:ty),*) => {
+    $(
+    SerializeComponents::<Infallible, SimpleMarker<SerializeMe>>::serialize(
+      &( $ecs.read_storage::<$type>(), ),
+      &$data.0,
+      &$data.1,
+      &mut $ser,
+    )
+    .map_err(|e| format!("Unable to write save file: {}", e))?;
+    )*
+  };
+}
+
+macro_rules! deserialize_individually {
+  ($ecs:expr, $de:expr, $data:expr, $( $type:ty),*) => {
+    $(
+    DeserializeComponents::<Infallible, _>::deserialize(
+      &mut ( &mut $ecs.write_storage::<$type>(), ),
+      &$data.0, // entities
+      &mut $data.1, // marker
+      &mut $data.2, // allocater
+      &mut $de,
+    )
+    .map_err(|e| format!("Corrupt save file: {}", e))?;
+    )*
+  };
+}
+
+pub fn does_save_exist(path : &str) -> bool {
+  Path::new(path).exists()
+}
+
+pub fn delete_save(path : &str) {
+  if does_save_exist(path) {
+    fs::remove_file(path).expect("Unable to delete file");
+  }
+}
+
+/// 読めなかったセーブファイルを path.bad に移し、移した先を返す。
+/// 残しておくとContinueが毎回同じように失敗するので、どかしておく (中身は調べられるよう消さない)
+pub fn set_aside_bad_save(path : &str) -> String {
+  let bad_path = format!("{}.bad", path);
+  if fs::rename(path, &bad_path).is_err() {
+    let _ = fs::remove_file(path);
+  }
+  bad_path
+}
+
+/// Worldのマーカー付きエンティティと、Map等のリソースをファイルに書き出す。
+/// 書けなかったときは (書きかけのファイルは消して) Errを返す。どうするかは呼び出し側が決める
+pub fn save_game(ecs : &mut World, path : &str) -> Result<(), String> {
+  // リソースをヘルパーエンティティに詰める
+  let runstate = match *ecs.fetch::<RunState>() {
+    RunState::PlayerTurn => SavedRunState::PlayerTurn,
+    RunState::Ticking => SavedRunState::Ticking,
+    _ => SavedRunState::AwaitingInput
+  };
+  let helper = SerializationHelper{
+    map : (*ecs.fetch::<Map>()).clone(),
+    log : (*ecs.fetch::<GameLog>()).clone(),
+    stats : (*ecs.fetch::<GameStats>()).clone(),
+    rng : (*ecs.fetch::<rltk::RandomNumberGenerator>()).clone(),
+    runstate
+  };
+  let savehelper = ecs
+    .create_entity()
+    .with(helper)
+    .marked::<SimpleMarker<SerializeMe>>()
+    .build();
+
+  // 失敗してもヘルパーは消す
+  let result = write_save(ecs, path);
+  ecs.delete_entity(savehelper).expect("Crash on cleanup");
+  if result.is_err() {
+    let _ = fs::remove_file(path);
+  }
+  result
+}
+
+fn write_save(ecs : &World, path : &str) -> Result<(), String> {
+  let data = ( ecs.entities(), ecs.read_storage::<SimpleMarker<SerializeMe>>() );
+
+  let file = File::create(path).map_err(|e| format!("Unable to create save file: {}", e))?;
+  let mut writer = BufWriter::new(file);
+  {
+    let mut serializer = serde_json::Serializer::new(&mut writer);
+    SaveHeader{ version: SAVE_VERSION }.serialize(&mut serializer).map_err(|e| format!("Unable to write save file: {}", e))?;
+    serialize_individually!(ecs, serializer, data, Position, Renderable, Player, Viewshed, Monster,
+      Name, BlocksTile, CombatStats, WantsToMelee, Item, InBackpack, WantsToPickupItem,
+      WantsToDropItem, Consumable, ProvidesHealing, Ranged, InflictsDamage, AreaOfEffect,
+      Confusion, WantsToUseItem, Initiative, MyTurn, Brain, Experience, ExperienceReward, Equippable, Equipped, MeleeWeapon, DefenseBonus,
+      Attributes, Skills, WantsToRemoveItem, SerializationHelper
+    );
+  }
+  // ディスクがいっぱいなどの書き込みエラーは、flushのときに出ることがある
+  writer.flush().map_err(|e| format!("Unable to write save file: {}", e))
+}
+
+/// セーブファイルを読み、ヘッダのバージョンを確かめて中身を返す。Worldには触らない
+pub fn read_save(path : &str) -> Result<String, String> {
+  let data = fs::read_to_string(path).map_err(|e| format!("Unable to read save file: {}", e))?;
+  let mut de = serde_json::Deserializer::from_str(&data);
+  let header = SaveHeader::deserialize(&mut de).map_err(|e| format!("Corrupt save file: {}", e))?;
+  if header.version != SAVE_VERSION {
+    return Err(format!("Save file version {} is not supported (expected {})", header.version, SAVE_VERSION));
+  }
+  Ok(data)
+}
+
+/// read_saveで読んだ中身からWorldを復元する。今のエンティティはすべて消える。
+/// セーブファイルを消す (パーマデス) のは呼び出し側
+pub fn load_game(ecs : &mut World, data : &str) -> Result<(), String> {
+  ecs.delete_all();
+
+  let mut de = serde_json::Deserializer::from_str(data);
+  // ヘッダはread_saveで確かめてあるので読み飛ばす
+  SaveHeader::deserialize(&mut de).map_err(|e| format!("Corrupt save file: {}", e))?;
+
+  {
+    let mut d = (&mut ecs.entities(), &mut ecs.write_storage::<SimpleMarker<SerializeMe>>(), &mut ecs.write_resource::<SimpleMarkerAllocator<SerializeMe>>());
+
+    deserialize_individually!(ecs, de, d, Position, Renderable, Player, Viewshed, Monster,
+      Name, BlocksTile, CombatStats, WantsToMelee, Item, InBackpack, WantsToPickupItem,
+      WantsToDropItem, Consumable, ProvidesHealing, Ranged, InflictsDamage, AreaOfEffect,
+      Confusion, WantsToUseItem, Initiative, MyTurn, Brain, Experience, ExperienceReward, Equippable, Equipped, MeleeWeapon, DefenseBonus,
+      Attributes, Skills, WantsToRemoveItem, SerializationHelper
+    );
+  }
+
+  // ヘルパーからリソースを戻す
+  let mut deleteme : Option<Entity> = None;
+  let mut runstate = SavedRunState::AwaitingInput;
+  let found_player;
+  {
+    let entities = ecs.entities();
+    let helper = ecs.read_storage::<SerializationHelper>();
+    let player = ecs.read_storage::<Player>();
+    let position = ecs.read_storage::<Position>();
+    for (e, h) in (&entities, &helper).join() {
+      let mut worldmap = ecs.write_resource::<Map>();
+      *worldmap = h.map.clone();
+      worldmap.tile_content = vec![Vec::new(); (worldmap.width * worldmap.height) as usize];
+      *ecs.write_resource::<GameLog>() = h.log.clone();
+      *ecs.write_resource::<GameStats>() = h.stats.clone();
+      *ecs.write_resource::<rltk::RandomNumberGenerator>() = h.rng.clone();
+      runstate = h.runstate;
+      deleteme = Some(e);
+    }
+    found_player = (&entities, &player, &position).join()
+      .map(|(e, _p, pos)| (e, rltk::Point::new(pos.x, pos.y)))
+      .next();
+  }
+  // 作り直したばかりのWorldにはプレイヤーのリソースがまだないので、insertで入れる
+  let (player_entity, player_pos) = found_player.ok_or("Corrupt save file: missing player")?;
+  ecs.insert(player_pos);
+  ecs.insert(player_entity);
+  let deleteme = deleteme.ok_or("Corrupt save file: missing world data")?;
+  ecs.delete_entity(deleteme).expect("Unable to delete helper");
+
+  // 入力待ちで保存したものはPreRunから再開して、MapのタイルごとのEntity一覧を作り直す
+  *ecs.write_resource::<RunState>() = match runstate {
+    SavedRunState::AwaitingInput => RunState::PreRun,
+    SavedRunState::PlayerTurn => RunState::PlayerTurn,
+    SavedRunState::Ticking => RunState::Ticking
+  };
+
+  Ok(())
+}
@@ -0,0 +1,99 @@
+use rltk::{RGB, RandomNumberGenerator};
+use specs::prelude::*;
+use std::collections::HashSet;
+use specs::saveload::{MarkedBuilder, SimpleMarker};
+use super::{SerializeMe, CombatStats, Player, Renderable, Name, Position, Viewshed, Map, TileType, Rect, Initiative, Experience,
+  Attributes, Skills, raws};
+
+/// プレイヤーを作ってエンティティを返す
+pub fn player(ecs : &mut World, player_x : i32, player_y : i32) -> Entity {
+  ecs
+    .create_entity()
+    .with(Position {x: player_x, y: player_y})
+    .with(Renderable {
+      glyph: rltk::to_cp437('@'),
+      fg: RGB::named(rltk::YELLOW),
+      bg: RGB::named(rltk::BLACK),
+      render_order: 0
+    })
+    .with(Player{})
+    .with(Viewshed{ visible_tiles : Vec::new(), range : 8, dirty: true })
+    .with(Name{ name: "Player".to_string() })
+    .with(CombatStats{ max_hp: 30, hp: 30 })
+    .with(Attributes{ might: 12, fitness: 12, quickness: 12, intelligence: 10 })
+    .with(Skills{ melee: 2, defense: 1 })
+    .with(Initiative{ energy: 0, speed: 100 })
+    .with(Experience{ level: 1, xp: 0, pending_level_ups: 0 })
+    .marked::<SimpleMarker<SerializeMe>>()
+    .build()
+}
+
+/// 1つの区画に置くものの数の上限 (階層1のとき)。深くなるほど増える
+const MAX_SPAWNS : i32 = 4;
+
+/// 部屋のないマップで、スタート地点からこの距離以内にはモンスターを置かない。プレイヤーの視界と同じ
+pub const START_SAFE_RADIUS : i32 = 8;
+
+/// 部屋の床のタイル
+pub fn room_tiles(map : &Map, room : &Rect) -> Vec<usize> {
+  let mut tiles = Vec::new();
+  for y in room.y1 + 1 ..= room.y2 {
+    for x in room.x1 + 1 ..= room.x2 {
+      tiles.push(map.xy_idx(x, y));
+    }
+  }
+  tiles
+}
+
+/// centerから距離radius以内のタイル
+pub fn tiles_within(map : &Map, center : usize, radius : i32) -> HashSet<usize> {
+  let (cx, cy) = (center as i32 % map.width, center as i32 / map.width);
+  let mut tiles = HashSet::new();
+  for y in i32::max(0, cy - radius) ..= i32::min(map.height - 1, cy + radius) {
+    for x in i32::max(0, cx - radius) ..= i32::min(map.width - 1, cx + radius) {
+      if (x - cx) * (x - cx) + (y - cy) * (y - cy) <= radius * radius {
+        tiles.insert(map.xy_idx(x, y));
+      }
+    }
+  }
+  tiles
+}
+
+/// 区画 (部屋や、部屋のないマップの領域) ごとに、出現表から0個以上のモンスターやアイテムを選んで置く。
+/// 置くのは区画の中の空いた床で、1つのタイルには1つだけ。プレイヤーのスタート地点 (start_idx) には置かない。
+/// safe_tiles (スタートの部屋など) にはアイテムだけ置き、モンスターを引いたら置かない。
+/// 始まってすぐ隣のモンスターに襲われないように
+pub fn spawn_areas(ecs : &mut World, map : &Map, areas : &[Vec<usize>], start_idx : usize, safe_tiles : &HashSet<usize>) {
+  let raws = raws::raws();
+  let table = raws.spawn_table_for_depth(map.depth);
+
+  // どこに何を置くかを先に全部決める
+  let mut spawns : Vec<(usize, String)> = Vec::new();
+  {
+    let mut rng = ecs.write_resource::<RandomNumberGenerator>();
+    for area in areas.iter() {
+      let mut candidates : Vec<usize> = area.iter().copied()
+        .filter(|idx| *idx != start_idx && map.tiles[*idx] == TileType::Floor)
+        .collect();
+      let count = (rng.roll_dice(1, MAX_SPAWNS + 3) + (map.depth - 1) - 3).max(0);
+      for _ in 0 .. count {
+        if candidates.is_empty() {
+          break;
+        }
+        let i = (rng.roll_dice(1, candidates.len() as i32) - 1) as usize;
+        let idx = candidates.remove(i);
+        if let Some(name) = table.roll(&mut rng) {
+          if safe_tiles.contains(&idx) && raws.is_monster(name) {
+            continue;
+          }
+          spawns.push((idx, name.to_string()));
+        }
+      }
+    }
+  }
+
+  // モンスターの名前には階層の中での通し番号を付ける
+  for (index, (idx, name)) in spawns.iter().enumerate() {
+    raws.spawn_named(ecs, name, *idx as i32 % map.width, *idx as i32 / map.width, index, map.depth);
+  }
+}